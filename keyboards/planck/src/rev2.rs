@@ -1,19 +1,27 @@
 use atmega_hal::{
     clock::MHz16,
     delay::Delay,
-    pac::{Peripherals, PLL, TC0, USB_DEVICE},
+    pac::{Peripherals, CPU, EEPROM, PLL, PORTB, PORTC, PORTD, PORTF, TC0, TC1, USB_DEVICE},
     port::mode::{Floating, Output},
-    port::mode::{Input, OpenDrain, PullUp},
+    port::mode::{Input, OpenDrain},
     port::{Pin, PB0, PB4, PB5, PB6, PC7, PD0, PD4, PD5, PD6, PD7, PF0, PF1, PF4, PF5, PF6, PF7},
     port::{PB7, PE6},
 };
 use atmega_usbd::UsbBus;
 use core::cmp::min;
+use core::convert::Infallible;
 use embedded_hal::blocking::delay::DelayUs;
 use polybius::{
+    debounce::NoDebounce,
     diodes::ColToRow,
+    encoder::NoEncoder,
     keyboard::Keyboard,
+    mutex::Mutex,
+    pin_group::{InputPort, PortPins},
+    power::SleepMode,
     scanner::{Direct, ScanMatrix},
+    storage::Storage,
+    time::Instant,
     uplink::usb::UsbHid,
 };
 use usb_device::{
@@ -32,31 +40,86 @@ pub type WriteLines = Direct<(
     Pin<OpenDrain, PB5>,
     Pin<OpenDrain, PB6>,
 )>;
-pub type ReadLines = Direct<(
-    Pin<Input<PullUp>, PF1>,
-    Pin<Input<PullUp>, PF0>,
-    Pin<Input<PullUp>, PB0>,
-    Pin<Input<PullUp>, PC7>,
-    Pin<Input<PullUp>, PF4>,
-    Pin<Input<PullUp>, PF5>,
-    Pin<Input<PullUp>, PF6>,
-    Pin<Input<PullUp>, PF7>,
-    Pin<Input<PullUp>, PD4>,
-    Pin<Input<PullUp>, PD6>,
-    Pin<Input<PullUp>, PB4>,
-    Pin<Input<PullUp>, PD7>,
-)>;
+pub type ReadLines = Direct<PortPins<Port, 4, COLS>>;
 pub type ScanDelay = fn();
 pub type Diodes = ColToRow;
-pub type Scanner = ScanMatrix<WriteLines, ReadLines, ScanDelay, Diodes, ROWS, COLS>;
+pub type Debouncer = NoDebounce;
+pub type Scanner = ScanMatrix<WriteLines, ReadLines, ScanDelay, Diodes, Debouncer, ROWS, COLS>;
 
 fn scan_delay() {
     let mut delay = Delay::<ClockSpeed>::new();
     delay.delay_us(30_u8);
 }
 
+/// The GPIO ports with read lines on them, so that all of the read lines can
+/// be sampled with four register reads.
+#[derive(Clone, Copy)]
+pub enum Port {
+    B,
+    C,
+    D,
+    F,
+}
+
+impl InputPort for Port {
+    type Error = Infallible;
+
+    fn read(&self) -> Result<u8, Self::Error> {
+        // SAFETY: The PINx registers are read-only, and reading them has no
+        // side effects.
+        let bits = unsafe {
+            match self {
+                Port::B => (*PORTB::ptr()).pinb.read().bits(),
+                Port::C => (*PORTC::ptr()).pinc.read().bits(),
+                Port::D => (*PORTD::ptr()).pind.read().bits(),
+                Port::F => (*PORTF::ptr()).pinf.read().bits(),
+            }
+        };
+        Ok(bits)
+    }
+}
+
 pub type Uplink = UsbHid<'static, UsbBus>;
 
+/// Milliseconds elapsed since the [`Clock`] was started.
+static MILLIS: Mutex<u32> = Mutex::new(0);
+
+/// A millisecond clock, counted by the Timer/Counter1 compare interrupt.
+///
+/// Interrupts must be enabled for the clock to advance. The timer is consumed
+/// when the clock is started, so that nothing else can reconfigure it, and the
+/// clock itself can be copied freely.
+#[derive(Clone, Copy)]
+pub struct Clock {
+    _private: (),
+}
+
+impl Clock {
+    pub fn new(tc1: TC1) -> Self {
+        // CTC mode, counting up to OCR1A at clk/64:
+        // 16 MHz / 64 / 250 = 1 kHz.
+        tc1.tccr1a.reset();
+        tc1.tccr1b
+            .write(|w| w.wgm1().bits(0b01).cs1().prescale_64());
+        tc1.ocr1a.write(|w| unsafe { w.bits(249) });
+        tc1.timsk1.write(|w| w.ocie1a().set_bit());
+
+        Self { _private: () }
+    }
+}
+
+impl polybius::time::Clock for Clock {
+    fn now(&self) -> Instant {
+        Instant::from_ticks(*MILLIS.lock())
+    }
+}
+
+#[avr_device::interrupt(atmega32u4)]
+fn TIMER1_COMPA() {
+    let mut millis = MILLIS.lock();
+    *millis = millis.wrapping_add(1);
+}
+
 pub struct Backlight {
     // 10.3.1: The PB7 pin can serve as an external output for the
     // Timer/Counter0 Output Compare.
@@ -118,10 +181,107 @@ impl polybius::backlight::Backlight for Backlight {
     }
 }
 
+/// Sleeps in the idle sleep mode, which is woken up by any interrupt,
+/// including the millisecond [`Clock`] and USB.
+///
+/// The Planck has no suspend support. Only two of its twelve read lines (PB0
+/// and PB4) have pin change interrupts, and none have external interrupts, so
+/// most keys can't wake up the deeper sleep modes. While the host is
+/// suspended, the keyboard keeps sleeping in the idle mode between scans
+/// instead, which saves less power.
+pub struct Power {
+    cpu: CPU,
+}
+
+impl polybius::power::Power for Power {
+    type Error = Infallible;
+
+    fn sleep(&mut self, mode: SleepMode) -> Result<(), Self::Error> {
+        match mode {
+            // No deeper sleep mode can be woken up by every key, so suspend
+            // sleeps the same way as idle.
+            SleepMode::Idle | SleepMode::Suspend => {
+                self.cpu.smcr.write(|w| w.sm().idle().se().set_bit());
+            }
+        }
+        avr_device::asm::sleep();
+        self.cpu.smcr.write(|w| w.se().clear_bit());
+        Ok(())
+    }
+}
+
+/// The 1 KiB of EEPROM built into the microcontroller.
+///
+/// Like the rest of this crate, it only builds for AVR targets.
+pub struct Eeprom {
+    eeprom: EEPROM,
+}
+
+const EEPROM_SIZE: usize = 1024;
+
+/// An error returned when accessing addresses past the end of the EEPROM.
+#[derive(Debug)]
+pub struct OutOfRange;
+
+impl Eeprom {
+    fn check_range(address: usize, len: usize) -> Result<(), OutOfRange> {
+        match address.checked_add(len) {
+            Some(end) if end <= EEPROM_SIZE => Ok(()),
+            _ => Err(OutOfRange),
+        }
+    }
+
+    fn wait_ready(&self) {
+        while self.eeprom.eecr.read().eepe().bit_is_set() {}
+    }
+}
+
+impl Storage for Eeprom {
+    type Error = OutOfRange;
+
+    fn read(&mut self, address: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        Self::check_range(address, buf.len())?;
+        for (i, byte) in buf.iter_mut().enumerate() {
+            self.wait_ready();
+            self.eeprom
+                .eear
+                .write(|w| unsafe { w.bits((address + i) as u16) });
+            self.eeprom.eecr.write(|w| w.eere().set_bit());
+            *byte = self.eeprom.eedr.read().bits();
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, address: usize, data: &[u8]) -> Result<(), Self::Error> {
+        Self::check_range(address, data.len())?;
+        for (i, &byte) in data.iter().enumerate() {
+            self.wait_ready();
+            self.eeprom
+                .eear
+                .write(|w| unsafe { w.bits((address + i) as u16) });
+            self.eeprom.eedr.write(|w| unsafe { w.bits(byte) });
+            // Setting EEMPE (bit 2 of EECR, at I/O address 0x1f) enables a
+            // write for the next four clock cycles, and setting EEPE (bit 1)
+            // within them starts it. Each `sbi` takes two cycles, so the pair
+            // meets the deadline as long as no interrupt runs between them,
+            // which the generated code for the register API doesn't
+            // guarantee.
+            avr_device::interrupt::free(|_| unsafe {
+                core::arch::asm!("sbi 0x1f, 2", "sbi 0x1f, 1");
+            });
+        }
+        Ok(())
+    }
+}
+
 pub struct PlanckRev2 {
+    clock: Clock,
     scanner: Scanner,
     uplink: Uplink,
     backlight: Backlight,
+    encoder: NoEncoder,
+    power: Power,
+    storage: Eeprom,
 }
 
 impl Keyboard<ROWS, COLS> for PlanckRev2 {
@@ -131,6 +291,12 @@ impl Keyboard<ROWS, COLS> for PlanckRev2 {
 
     type Backlight = Backlight;
 
+    type Encoder = NoEncoder;
+
+    type Power = Power;
+
+    type Storage = Eeprom;
+
     fn scanner(&mut self) -> &mut Self::Scanner {
         &mut self.scanner
     }
@@ -142,12 +308,28 @@ impl Keyboard<ROWS, COLS> for PlanckRev2 {
     fn backlight(&mut self) -> &mut Self::Backlight {
         &mut self.backlight
     }
+
+    fn encoder(&mut self) -> &mut Self::Encoder {
+        &mut self.encoder
+    }
+
+    fn power(&mut self) -> &mut Self::Power {
+        &mut self.power
+    }
+
+    fn storage(&mut self) -> &mut Self::Storage {
+        &mut self.storage
+    }
 }
 
 impl PlanckRev2 {
     /// Initialize the keyboard, taking full ownership of the device
     /// peripherals.
     ///
+    /// The millisecond [`Clock`] is started as well, and can be passed to the
+    /// `System` from [`PlanckRev2::clock`]; it only advances once interrupts
+    /// are enabled.
+    ///
     /// # Panics
     ///
     /// This function calls `atmega_hal::Peripherals::take()` and will panic if
@@ -166,8 +348,11 @@ impl PlanckRev2 {
     ///
     /// The [`from_parts!`] macro is a more convenient way to call this method.
     pub fn from_parts(
+        cpu: CPU,
+        eeprom: EEPROM,
         pll: PLL,
         tc0: TC0,
+        tc1: TC1,
         usb_device: USB_DEVICE,
         pb0: Pin<Input<Floating>, PB0>,
         pb4: Pin<Input<Floating>, PB4>,
@@ -219,21 +404,38 @@ impl PlanckRev2 {
             pb5.into_opendrain_high(),
             pb6.into_opendrain_high(),
         ));
-        let read_lines = Direct((
-            pf1.into_pull_up_input(),
-            pf0.into_pull_up_input(),
-            pb0.into_pull_up_input(),
-            pc7.into_pull_up_input(),
-            pf4.into_pull_up_input(),
-            pf5.into_pull_up_input(),
-            pf6.into_pull_up_input(),
-            pf7.into_pull_up_input(),
-            pd4.into_pull_up_input(),
-            pd6.into_pull_up_input(),
-            pb4.into_pull_up_input(),
-            pd7.into_pull_up_input(),
+        // The read pins stay configured after being dropped; they are read
+        // through their port registers instead.
+        pf1.into_pull_up_input();
+        pf0.into_pull_up_input();
+        pb0.into_pull_up_input();
+        pc7.into_pull_up_input();
+        pf4.into_pull_up_input();
+        pf5.into_pull_up_input();
+        pf6.into_pull_up_input();
+        pf7.into_pull_up_input();
+        pd4.into_pull_up_input();
+        pd6.into_pull_up_input();
+        pb4.into_pull_up_input();
+        pd7.into_pull_up_input();
+        let read_lines = Direct(PortPins::new(
+            [Port::B, Port::C, Port::D, Port::F],
+            [
+                (3, 1),
+                (3, 0),
+                (0, 0),
+                (1, 7),
+                (3, 4),
+                (3, 5),
+                (3, 6),
+                (3, 7),
+                (2, 4),
+                (2, 6),
+                (0, 4),
+                (2, 7),
+            ],
         ));
-        let scanner = Scanner::new(write_lines, read_lines, scan_delay);
+        let scanner = Scanner::new(write_lines, read_lines, scan_delay, NoDebounce);
 
         static mut USB_BUS: Option<UsbBusAllocator<UsbBus>> = None;
         let usb_bus: &'static UsbBusAllocator<UsbBus> =
@@ -249,15 +451,25 @@ impl PlanckRev2 {
         });
 
         let backlight = Backlight::new(pb7, tc0);
+        let clock = Clock::new(tc1);
 
         let _status = pe6.into_output_high();
 
         Self {
+            clock,
             scanner,
             uplink,
             backlight,
+            encoder: NoEncoder,
+            power: Power { cpu },
+            storage: Eeprom { eeprom },
         }
     }
+
+    /// The millisecond clock, started by [`PlanckRev2::from_parts`].
+    pub fn clock(&self) -> Clock {
+        self.clock
+    }
 }
 
 /// Initialize the keyboard, taking ownership of only the peripherals
@@ -271,17 +483,21 @@ impl PlanckRev2 {
 /// let peripherals = atmega_hal::Peripherals::take().unwrap();
 /// let pins = atmega_hal::pins!(peripherals);
 /// let keyboard: PlanckRev2 = polybius_planck::rev2::from_parts!(peripherals, pins);
+/// let clock = keyboard.clock();
 ///
 /// // Can still take other parts:
-/// let tc1 = peripherals.TC3;
+/// let tc3 = peripherals.TC3;
 /// let pc6 = pins.pc6;
 /// ```
 #[macro_export]
 macro_rules! planck_rev2 {
     ($dp:expr, $pins:expr) => {
         $crate::rev2::PlanckRev2::from_parts(
+            $dp.CPU,
+            $dp.EEPROM,
             $dp.PLL,
             $dp.TC0,
+            $dp.TC1,
             $dp.USB_DEVICE,
             $pins.pb0,
             $pins.pb4,
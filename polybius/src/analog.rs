@@ -0,0 +1,606 @@
+//! Analog key scanning.
+//!
+//! Analog switches, like magnetic hall-effect switches, report how far they
+//! are pressed down instead of whether they are pressed. The
+//! [`AnalogScanner`] samples each switch with an ADC, converts each sample
+//! into a distance of travel using the key's [`Calibration`], and decides
+//! whether the key is pressed according to its [`Actuation`] settings.
+
+use crate::debounce::NoDebounce;
+use crate::diodes::{DiodeConfiguration, KeyPosition, ScanPosition};
+use crate::pin_group::OutputGroup;
+use crate::scanner::{row_bytes, KeyEvent, MatrixState, ScanRow, Scanner};
+use crate::time::Instant;
+use core::cmp::{max, min};
+use core::marker::PhantomData;
+use embedded_hal::adc::{Channel, OneShot};
+
+/// A fixed-size set of analog lines, each connected to one switch.
+pub trait AnalogReadLines<const LEN: usize> {
+    type Error;
+
+    /// Samples the line at `index`, returning the raw ADC reading.
+    fn sample(&mut self, index: usize) -> Result<u16, Self::Error>;
+}
+
+/// [`AnalogReadLines`] behind an analog multiplexer, like the 74HC4067.
+///
+/// The ADC samples a single analog pin, `pin`, which is connected to the
+/// common output of the multiplexer. The multiplexer's channel is selected by
+/// the `BITS` pins in `select`, in binary, least significant bit first; line
+/// `i` is channel `i` of the multiplexer.
+///
+/// `settle_delay` is called after selecting a channel and before sampling it,
+/// to give the signal time to settle.
+pub struct Multiplexed<A, M, P, S, D, const BITS: usize> {
+    adc: A,
+    pin: P,
+    select: S,
+    settle_delay: D,
+    _adc: PhantomData<M>,
+}
+
+impl<A, M, P, S, D, const BITS: usize> Multiplexed<A, M, P, S, D, BITS>
+where
+    A: OneShot<M, u16, P>,
+    P: Channel<M>,
+    S: OutputGroup<BITS>,
+    D: FnMut(),
+{
+    pub fn new(adc: A, pin: P, select: S, settle_delay: D) -> Self {
+        Self {
+            adc,
+            pin,
+            select,
+            settle_delay,
+            _adc: PhantomData,
+        }
+    }
+}
+
+/// An error from one of the parts of a [`Multiplexed`].
+#[derive(Debug)]
+pub enum MultiplexedError<A, S> {
+    /// The ADC failed to sample the pin.
+    Adc(A),
+    /// The multiplexer's channel could not be selected.
+    Select(S),
+}
+
+/// Checks that `LEN` lines can be selected with `BITS` select lines.
+pub(crate) struct ChannelCount<const LEN: usize, const BITS: usize>;
+
+impl<const LEN: usize, const BITS: usize> ChannelCount<LEN, BITS> {
+    pub(crate) const CHECK: () = assert!(
+        BITS < usize::BITS as usize && LEN <= 1 << BITS,
+        "not enough select lines for the number of analog lines"
+    );
+}
+
+/// Selects channel `index` of a multiplexer, by setting its select lines to
+/// the binary representation of `index`.
+pub(crate) fn select_channel<S, const BITS: usize>(
+    select: &mut S,
+    index: usize,
+) -> Result<(), S::Error>
+where
+    S: OutputGroup<BITS>,
+{
+    for bit in 0..BITS {
+        if index & (1 << bit) != 0 {
+            select.set_high(bit)?;
+        } else {
+            select.set_low(bit)?;
+        }
+    }
+    Ok(())
+}
+
+impl<A, M, P, S, D, const BITS: usize, const LEN: usize> AnalogReadLines<LEN>
+    for Multiplexed<A, M, P, S, D, BITS>
+where
+    A: OneShot<M, u16, P>,
+    P: Channel<M>,
+    S: OutputGroup<BITS>,
+    D: FnMut(),
+{
+    type Error = MultiplexedError<A::Error, S::Error>;
+
+    fn sample(&mut self, index: usize) -> Result<u16, Self::Error> {
+        let () = ChannelCount::<LEN, BITS>::CHECK;
+        select_channel(&mut self.select, index).map_err(MultiplexedError::Select)?;
+        (self.settle_delay)();
+        nb::block!(self.adc.read(&mut self.pin)).map_err(MultiplexedError::Adc)
+    }
+}
+
+/// The raw ADC readings of a key at both ends of its travel.
+///
+/// The readings may increase or decrease as the key is pressed, depending on
+/// the orientation of the magnet.
+#[derive(Clone, Copy)]
+pub struct Calibration {
+    /// The reading while the key is fully released.
+    pub rest: u16,
+    /// The reading while the key is fully pressed.
+    pub bottom: u16,
+}
+
+impl Calibration {
+    /// Converts a raw reading into the distance of travel, where 0 is fully
+    /// released and 255 is fully pressed.
+    pub fn travel(&self, raw: u16) -> u8 {
+        let (offset, range) = if self.bottom >= self.rest {
+            (raw.saturating_sub(self.rest), self.bottom - self.rest)
+        } else {
+            (self.rest.saturating_sub(raw), self.rest - self.bottom)
+        };
+        if range == 0 {
+            return 0;
+        }
+        (u32::from(min(offset, range)) * 255 / u32::from(range)) as u8
+    }
+
+    /// Whether `raw` reads past the bottom of the current range.
+    fn is_past_bottom(&self, raw: u16) -> bool {
+        if self.bottom >= self.rest {
+            raw > self.bottom
+        } else {
+            raw < self.bottom
+        }
+    }
+
+    /// The one of `a` and `b` that is closer to the rest reading.
+    fn shallower(&self, a: u16, b: u16) -> u16 {
+        if self.bottom >= self.rest {
+            min(a, b)
+        } else {
+            max(a, b)
+        }
+    }
+}
+
+/// The number of consecutive readings past the calibrated bottom after which
+/// the calibration is extended, so that a single noisy reading doesn't shrink
+/// the travel of every later press.
+const EXTEND_SAMPLES: u8 = 4;
+
+/// When an analog key is considered pressed, in units of travel as returned
+/// by [`Calibration::travel`].
+#[derive(Clone, Copy)]
+pub struct Actuation {
+    /// The key is pressed when its travel reaches this point.
+    pub press: u8,
+    /// The key is released when its travel goes back below this point.
+    ///
+    /// This should be lower than `press`, to keep noise around the actuation
+    /// point from toggling the key.
+    pub release: u8,
+    /// Rapid trigger sensitivity, if enabled.
+    ///
+    /// With rapid trigger, once the key has been pressed, it is released as
+    /// soon as it moves up by this much from the deepest point it reached, and
+    /// pressed again as soon as it moves down by this much from the shallowest
+    /// point it reached since, until it goes back up above the `release`
+    /// point.
+    pub rapid_trigger: Option<u8>,
+}
+
+/// The state of a single analog key.
+#[derive(Clone, Copy)]
+struct AnalogKey {
+    calibration: Calibration,
+    actuation: Actuation,
+    travel: u8,
+    pressed: bool,
+    /// The deepest travel since the key was pressed, or the shallowest travel
+    /// since it was released.
+    extreme: u8,
+    /// Whether the key was released by rapid trigger, and can be pressed again
+    /// by rapid trigger.
+    rapid: bool,
+    /// The number of consecutive readings past the calibrated bottom.
+    past_bottom: u8,
+    /// The shallowest of those readings, which the bottom is extended to.
+    past_bottom_reading: u16,
+}
+
+impl AnalogKey {
+    /// Extends the calibration once enough consecutive readings, up to and
+    /// including `raw`, read past its bottom.
+    fn extend(&mut self, raw: u16) {
+        if !self.calibration.is_past_bottom(raw) {
+            self.past_bottom = 0;
+            return;
+        }
+        self.past_bottom_reading = match self.past_bottom {
+            0 => raw,
+            _ => self.calibration.shallower(self.past_bottom_reading, raw),
+        };
+        self.past_bottom += 1;
+        if self.past_bottom >= EXTEND_SAMPLES {
+            self.calibration.bottom = self.past_bottom_reading;
+            self.past_bottom = 0;
+        }
+    }
+
+    /// Updates the state of the key with a new raw reading, and returns whether
+    /// the key is pressed.
+    fn update(&mut self, raw: u16, auto_calibrate: bool) -> bool {
+        if auto_calibrate {
+            self.extend(raw);
+        }
+        let travel = self.calibration.travel(raw);
+        self.travel = travel;
+
+        let Actuation {
+            press,
+            release,
+            rapid_trigger,
+        } = self.actuation;
+        if self.pressed {
+            self.extreme = max(self.extreme, travel);
+            let rapid_release =
+                rapid_trigger.is_some_and(|s| travel <= self.extreme.saturating_sub(s));
+            if travel < release || rapid_release {
+                self.pressed = false;
+                self.extreme = travel;
+                self.rapid = rapid_release && travel >= release;
+            }
+        } else {
+            self.extreme = min(self.extreme, travel);
+            if travel < release {
+                self.rapid = false;
+            }
+            let rapid_press = self.rapid
+                && rapid_trigger.is_some_and(|s| travel >= self.extreme.saturating_add(s));
+            if travel >= press || rapid_press {
+                self.pressed = true;
+                self.extreme = travel;
+            }
+        }
+        self.pressed
+    }
+}
+
+/// A scanner for analog switches, each sampled on its own analog line.
+///
+/// Like [`DirectPins`](crate::scanner::DirectPins), the mapping from lines to
+/// logical key positions is given by the configuration `C`, which must have
+/// exactly one write line.
+///
+/// Every key starts out with the same calibration and actuation settings,
+/// which can then be changed per key. By default, the calibration is also
+/// extended automatically whenever a key reads further than its calibrated
+/// bottom for several scans in a row; see
+/// [`set_auto_calibrate`](Self::set_auto_calibrate).
+pub struct AnalogScanner<L, C, const ROWS: usize, const COLS: usize>
+where
+    [(); row_bytes(COLS)]:,
+{
+    lines: L,
+    keys: [[AnalogKey; COLS]; ROWS],
+    auto_calibrate: bool,
+    state: MatrixState<C, NoDebounce, ROWS, COLS>,
+}
+
+impl<L, C, const ROWS: usize, const COLS: usize> AnalogScanner<L, C, ROWS, COLS>
+where
+    C: DiodeConfiguration<ROWS, COLS>,
+    L: AnalogReadLines<{ C::READ_LINES }>,
+    [(); row_bytes(COLS)]:,
+{
+    const ONE_WRITE_LINE: () = assert!(
+        C::WRITE_LINES == 1,
+        "analog scanner configuration must have a single write line"
+    );
+
+    pub fn new(lines: L, calibration: Calibration, actuation: Actuation) -> Self {
+        let () = Self::ONE_WRITE_LINE;
+        Self {
+            lines,
+            keys: [[AnalogKey {
+                calibration,
+                actuation,
+                travel: 0,
+                pressed: false,
+                extreme: 0,
+                rapid: false,
+                past_bottom: 0,
+                past_bottom_reading: 0,
+            }; COLS]; ROWS],
+            auto_calibrate: true,
+            state: MatrixState::new(NoDebounce),
+        }
+    }
+
+    /// Samples every key, and uses the readings as their rest readings.
+    ///
+    /// This should be called while none of the keys are pressed, for example
+    /// at startup.
+    pub fn calibrate_rest(&mut self) -> Result<(), L::Error> {
+        for row in 0..ROWS {
+            for col in 0..COLS {
+                let ScanPosition { read_index, .. } = C::scan_position(KeyPosition { row, col });
+                self.keys[row][col].calibration.rest = self.lines.sample(read_index)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn calibration(&self, row: usize, col: usize) -> Calibration {
+        self.keys[row][col].calibration
+    }
+
+    pub fn set_calibration(&mut self, row: usize, col: usize, calibration: Calibration) {
+        let key = &mut self.keys[row][col];
+        key.calibration = calibration;
+        key.past_bottom = 0;
+    }
+
+    /// Sets whether the bottom readings are extended automatically, when a
+    /// key reads past its calibrated bottom for several scans in a row. This
+    /// is enabled by default; disable it to keep fixed calibrations, e.g. ones
+    /// loaded from storage.
+    pub fn set_auto_calibrate(&mut self, enabled: bool) {
+        self.auto_calibrate = enabled;
+    }
+
+    pub fn actuation(&self, row: usize, col: usize) -> Actuation {
+        self.keys[row][col].actuation
+    }
+
+    pub fn set_actuation(&mut self, row: usize, col: usize, actuation: Actuation) {
+        self.keys[row][col].actuation = actuation;
+    }
+
+    /// The travel of the key as of the last call to [`poll()`](Scanner::poll),
+    /// where 0 is fully released and 255 is fully pressed.
+    pub fn travel(&self, row: usize, col: usize) -> u8 {
+        self.keys[row][col].travel
+    }
+}
+
+impl<L, C, const ROWS: usize, const COLS: usize> Scanner<ROWS, COLS>
+    for AnalogScanner<L, C, ROWS, COLS>
+where
+    C: DiodeConfiguration<ROWS, COLS>,
+    L: AnalogReadLines<{ C::READ_LINES }>,
+    [(); row_bytes(COLS)]:,
+    [(); row_bytes(C::READ_LINES)]:,
+    [(); C::WRITE_LINES]:,
+{
+    type Error = L::Error;
+
+    fn poll(&mut self, now: Instant) -> Result<(), Self::Error> {
+        let mut scan_lines = [ScanRow::<{ C::READ_LINES }>::EMPTY; C::WRITE_LINES];
+        for j in 0..C::READ_LINES {
+            let Some(KeyPosition { row, col }) = C::key_position(ScanPosition {
+                write_index: 0,
+                read_index: j,
+            }) else {
+                continue;
+            };
+            let raw = self.lines.sample(j)?;
+            scan_lines[0].set(j, self.keys[row][col].update(raw, self.auto_calibrate));
+        }
+        self.state.update(now, &scan_lines);
+        Ok(())
+    }
+
+    fn is_pressed(&self, row: usize, col: usize) -> bool {
+        self.state.is_pressed(row, col)
+    }
+
+    fn just_pressed(&self, row: usize, col: usize) -> bool {
+        self.state.just_pressed(row, col)
+    }
+
+    fn just_released(&self, row: usize, col: usize) -> bool {
+        self.state.just_released(row, col)
+    }
+
+    fn for_each_event<F>(&self, timestamp: Instant, f: F)
+    where
+        F: FnMut(KeyEvent),
+    {
+        self.state.for_each_event(timestamp, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::diodes::RowMajor;
+
+    use core::convert::Infallible;
+    use embedded_hal_mock::adc::{self, MockChan0};
+    use embedded_hal_mock::pin::{self, State};
+    use std::string::String;
+    use std::vec;
+    use std::vec::Vec;
+
+    /// Analog lines that replay the given raw readings for each line, one
+    /// reading per sample.
+    struct FakeLines<const LEN: usize> {
+        readings: [std::vec::IntoIter<u16>; LEN],
+    }
+
+    impl<const LEN: usize> FakeLines<LEN> {
+        fn new(readings: [Vec<u16>; LEN]) -> Self {
+            Self {
+                readings: readings.map(Vec::into_iter),
+            }
+        }
+    }
+
+    impl<const LEN: usize> AnalogReadLines<LEN> for FakeLines<LEN> {
+        type Error = Infallible;
+
+        fn sample(&mut self, index: usize) -> Result<u16, Self::Error> {
+            Ok(self.readings[index].next().unwrap())
+        }
+    }
+
+    const CALIBRATION: Calibration = Calibration {
+        rest: 2000,
+        bottom: 1000,
+    };
+
+    /// Converts a sequence of travel percentages into raw readings, for the
+    /// default calibration.
+    fn curve(travel: &[u16]) -> Vec<u16> {
+        travel.iter().map(|&t| 2000 - t * 10).collect()
+    }
+
+    /// Polls the scanner once per reading, returning the edges reported for
+    /// the key at (0, 0) (`P` just pressed, `R` just released, `-` no
+    /// change).
+    fn edges<S: Scanner<1, 1>>(scanner: &mut S, scans: usize) -> String {
+        (0..scans)
+            .map(|_| {
+                scanner.poll(Instant::from_ticks(0)).ok().unwrap();
+                if scanner.just_pressed(0, 0) {
+                    'P'
+                } else if scanner.just_released(0, 0) {
+                    'R'
+                } else {
+                    '-'
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn calibration_travel() {
+        assert_eq!(CALIBRATION.travel(2100), 0);
+        assert_eq!(CALIBRATION.travel(2000), 0);
+        assert_eq!(CALIBRATION.travel(1500), 127);
+        assert_eq!(CALIBRATION.travel(1000), 255);
+        assert_eq!(CALIBRATION.travel(900), 255);
+
+        let inverted = Calibration {
+            rest: 1000,
+            bottom: 2000,
+        };
+        assert_eq!(inverted.travel(1500), 127);
+    }
+
+    #[test]
+    fn actuation_point() {
+        let travel = [0, 20, 40, 55, 45, 40, 45, 60, 100, 60, 35, 30, 40, 0];
+        let lines = FakeLines::new([curve(&travel)]);
+        let actuation = Actuation {
+            press: 128,
+            release: 96,
+            rapid_trigger: None,
+        };
+        let mut scanner: AnalogScanner<_, RowMajor, 1, 1> =
+            AnalogScanner::new(lines, CALIBRATION, actuation);
+
+        // Wobbling between the release and press points has no effect.
+        assert_eq!(edges(&mut scanner, travel.len()), "---P------R---");
+    }
+
+    #[test]
+    fn rapid_trigger() {
+        let travel = [0, 60, 80, 75, 70, 80, 90, 85, 30, 45, 20, 30];
+        let lines = FakeLines::new([curve(&travel)]);
+        let actuation = Actuation {
+            press: 128,
+            release: 96,
+            rapid_trigger: Some(25),
+        };
+        let mut scanner: AnalogScanner<_, RowMajor, 1, 1> =
+            AnalogScanner::new(lines, CALIBRATION, actuation);
+
+        // Released on each upstroke and pressed again on each downstroke, until
+        // the key goes back up above the release point.
+        assert_eq!(edges(&mut scanner, travel.len()), "-P--RP--R---");
+    }
+
+    #[test]
+    fn calibrate_rest() {
+        // A rest reading of 1900 places 1450 at the middle of the travel.
+        let lines = FakeLines::new([vec![1900, 1450]]);
+        let mut scanner: AnalogScanner<_, RowMajor, 1, 1> = AnalogScanner::new(
+            lines,
+            CALIBRATION,
+            Actuation {
+                press: 128,
+                release: 96,
+                rapid_trigger: None,
+            },
+        );
+        scanner.calibrate_rest().unwrap();
+        scanner.poll(Instant::from_ticks(0)).unwrap();
+        assert_eq!(scanner.travel(0, 0), 127);
+        assert!(!scanner.is_pressed(0, 0));
+    }
+
+    #[test]
+    fn extend_bottom() {
+        let actuation = Actuation {
+            press: 128,
+            release: 96,
+            rapid_trigger: None,
+        };
+
+        // A single spike past the bottom is ignored.
+        let lines = FakeLines::new([vec![2000, 500, 1000]]);
+        let mut scanner: AnalogScanner<_, RowMajor, 1, 1> =
+            AnalogScanner::new(lines, CALIBRATION, actuation);
+        edges(&mut scanner, 3);
+        assert_eq!(scanner.calibration(0, 0).bottom, 1000);
+        assert_eq!(scanner.travel(0, 0), 255);
+
+        // Readings that stay past the bottom extend it to the shallowest of
+        // them.
+        let lines = FakeLines::new([vec![900, 850, 880, 870, 1000]]);
+        let mut scanner: AnalogScanner<_, RowMajor, 1, 1> =
+            AnalogScanner::new(lines, CALIBRATION, actuation);
+        edges(&mut scanner, 3);
+        assert_eq!(scanner.calibration(0, 0).bottom, 1000);
+        edges(&mut scanner, 1);
+        assert_eq!(scanner.calibration(0, 0).bottom, 900);
+        edges(&mut scanner, 1);
+        assert_eq!(scanner.travel(0, 0), 231);
+
+        // Unless automatic calibration is disabled.
+        let lines = FakeLines::new([vec![900, 850, 880, 870]]);
+        let mut scanner: AnalogScanner<_, RowMajor, 1, 1> =
+            AnalogScanner::new(lines, CALIBRATION, actuation);
+        scanner.set_auto_calibrate(false);
+        edges(&mut scanner, 4);
+        assert_eq!(scanner.calibration(0, 0).bottom, 1000);
+    }
+
+    #[test]
+    fn multiplexed_select() {
+        let mut adc = adc::Mock::new(&[
+            adc::Transaction::read(0, 1234),
+            adc::Transaction::read(0, 4321),
+        ]);
+        let mut select_pins = [
+            pin::Mock::new(&[
+                pin::Transaction::set(State::High),
+                pin::Transaction::set(State::Low),
+            ]),
+            pin::Mock::new(&[
+                pin::Transaction::set(State::Low),
+                pin::Transaction::set(State::High),
+            ]),
+        ];
+
+        let mut lines = Multiplexed::new(adc.clone(), MockChan0, select_pins.clone(), || {});
+        assert_eq!(AnalogReadLines::<4>::sample(&mut lines, 1).ok(), Some(1234));
+        assert_eq!(AnalogReadLines::<4>::sample(&mut lines, 2).ok(), Some(4321));
+
+        adc.done();
+        for pin in &mut select_pins {
+            pin.done();
+        }
+    }
+}
@@ -0,0 +1,357 @@
+//! Scanning in the background, from an interrupt handler.
+//!
+//! Normally, the matrix is scanned inline in [`System::poll`], so the scan
+//! rate depends on how long the rest of the system (e.g. USB handling) takes.
+//! Instead, a scanner can be wrapped in a [`Background`] and polled from a
+//! timer interrupt at a fixed rate, pushing its key events into an
+//! [`EventQueue`]. The [`Receiver`] on the other end of the queue is a
+//! [`Scanner`] that replays those events, with their original timestamps, to
+//! the system.
+//!
+//! # Example
+//!
+//! ```ignore
+//! static QUEUE: EventQueue<4, 12, 16> = EventQueue::new();
+//! static SCANNER: Mutex<Option<Background<'static, Matrix, 4, 12, 16>>> = Mutex::new(None);
+//!
+//! #[avr_device::interrupt(atmega32u4)]
+//! fn TIMER3_COMPA() {
+//!     if let Some(scanner) = SCANNER.lock().as_mut() {
+//!         let _ = scanner.scan(clock.now());
+//!     }
+//! }
+//!
+//! // The keyboard uses `Receiver::new(&QUEUE)` as its scanner.
+//! ```
+//!
+//! [`System::poll`]: crate::system::System::poll
+
+use fullhouse::Deque;
+
+use core::convert::Infallible;
+
+use crate::keycode::KeyAction;
+use crate::mutex::Mutex;
+use crate::scanner::{row_bytes, KeyEvent, ScanRow, Scanner};
+use crate::time::Instant;
+
+struct Shared<const ROWS: usize, const COLS: usize, const N: usize>
+where
+    [(); row_bytes(COLS)]:,
+{
+    events: Deque<KeyEvent, N>,
+    /// Whether any events were dropped because the queue was full.
+    overflowed: bool,
+    /// The keys that were pressed as of the last scan, and its time, which
+    /// are kept up to date after an overflow so that the receiver can catch
+    /// up with the dropped events.
+    snapshot: [ScanRow<COLS>; ROWS],
+    snapshot_at: Instant,
+}
+
+/// A queue of up to `N` key events of a `ROWS` by `COLS` matrix, shared
+/// between a [`Background`] scanner and a [`Receiver`].
+///
+/// If the queue overflows, the receiver catches up with a snapshot of the
+/// pressed keys instead, so that no key is left stuck.
+pub struct EventQueue<const ROWS: usize, const COLS: usize, const N: usize>
+where
+    [(); row_bytes(COLS)]:,
+{
+    shared: Mutex<Shared<ROWS, COLS, N>>,
+}
+
+impl<const ROWS: usize, const COLS: usize, const N: usize> EventQueue<ROWS, COLS, N>
+where
+    [(); row_bytes(COLS)]:,
+{
+    pub const fn new() -> Self {
+        Self {
+            shared: Mutex::new(Shared {
+                events: Deque::new(),
+                overflowed: false,
+                snapshot: [ScanRow::EMPTY; ROWS],
+                snapshot_at: Instant::from_ticks(0),
+            }),
+        }
+    }
+}
+
+impl<const ROWS: usize, const COLS: usize, const N: usize> Default for EventQueue<ROWS, COLS, N>
+where
+    [(); row_bytes(COLS)]:,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A scanner that is polled from an interrupt handler, sending its key events
+/// to a [`Receiver`].
+pub struct Background<'a, S, const ROWS: usize, const COLS: usize, const N: usize>
+where
+    [(); row_bytes(COLS)]:,
+{
+    scanner: S,
+    queue: &'a EventQueue<ROWS, COLS, N>,
+}
+
+impl<'a, S, const ROWS: usize, const COLS: usize, const N: usize> Background<'a, S, ROWS, COLS, N>
+where
+    S: Scanner<ROWS, COLS>,
+    [(); row_bytes(COLS)]:,
+{
+    pub fn new(scanner: S, queue: &'a EventQueue<ROWS, COLS, N>) -> Self {
+        Self { scanner, queue }
+    }
+
+    pub fn scanner(&mut self) -> &mut S {
+        &mut self.scanner
+    }
+
+    /// Scans the keys, and queues the resulting key events.
+    ///
+    /// This should be called at a fixed rate, usually from a timer interrupt.
+    pub fn scan(&mut self, now: Instant) -> Result<(), S::Error> {
+        self.scanner.poll(now)?;
+        let mut shared = self.queue.shared.lock();
+        self.scanner.for_each_event(now, |event| {
+            if shared.events.push_back(event).is_err() {
+                shared.overflowed = true;
+            }
+        });
+        if shared.overflowed {
+            for row in 0..ROWS {
+                for col in 0..COLS {
+                    shared.snapshot[row].set(col, self.scanner.is_pressed(row, col));
+                }
+            }
+            shared.snapshot_at = now;
+        }
+        Ok(())
+    }
+}
+
+/// A scanner that replays the key events sent by a [`Background`] scanner.
+///
+/// Each poll takes all of the queued events, which are then emitted by
+/// [`for_each_event()`](Scanner::for_each_event) with the timestamps of the
+/// scans that detected them.
+///
+/// If events were dropped because the queue was full, the poll also catches
+/// up with the keys that changed since the queued events, and emits an event
+/// for each of them with the time of the latest scan. Keys that were pressed
+/// and released again in the meantime are missed, but none are left stuck.
+pub struct Receiver<'a, const ROWS: usize, const COLS: usize, const N: usize>
+where
+    [(); row_bytes(COLS)]:,
+{
+    queue: &'a EventQueue<ROWS, COLS, N>,
+    events: [Option<KeyEvent>; N],
+    /// After an overflow, the keys that were pressed as of the queued events,
+    /// and the time of the snapshot that `pressed` was caught up with.
+    resync: Option<([ScanRow<COLS>; ROWS], Instant)>,
+    pressed: [ScanRow<COLS>; ROWS],
+    last_pressed: [ScanRow<COLS>; ROWS],
+}
+
+impl<'a, const ROWS: usize, const COLS: usize, const N: usize> Receiver<'a, ROWS, COLS, N>
+where
+    [(); row_bytes(COLS)]:,
+{
+    pub fn new(queue: &'a EventQueue<ROWS, COLS, N>) -> Self {
+        Self {
+            queue,
+            events: [None; N],
+            resync: None,
+            pressed: [ScanRow::EMPTY; ROWS],
+            last_pressed: [ScanRow::EMPTY; ROWS],
+        }
+    }
+}
+
+impl<'a, const ROWS: usize, const COLS: usize, const N: usize> Scanner<ROWS, COLS>
+    for Receiver<'a, ROWS, COLS, N>
+where
+    [(); row_bytes(COLS)]:,
+{
+    type Error = Infallible;
+
+    fn poll(&mut self, now: Instant) -> Result<(), Self::Error> {
+        let _ = now;
+        self.last_pressed = self.pressed;
+        let mut shared = self.queue.shared.lock();
+        for slot in &mut self.events {
+            *slot = shared.events.pop_front();
+            if let Some(event) = slot {
+                self.pressed[event.row].set(event.col, event.action.is_pressed());
+            }
+        }
+        self.resync = None;
+        if core::mem::take(&mut shared.overflowed) {
+            self.resync = Some((self.pressed, shared.snapshot_at));
+            self.pressed = shared.snapshot;
+        }
+        Ok(())
+    }
+
+    fn is_pressed(&self, row: usize, col: usize) -> bool {
+        self.pressed[row].get(col)
+    }
+
+    fn just_pressed(&self, row: usize, col: usize) -> bool {
+        (self.pressed[row] & !self.last_pressed[row]).get(col)
+    }
+
+    fn just_released(&self, row: usize, col: usize) -> bool {
+        (!self.pressed[row] & self.last_pressed[row]).get(col)
+    }
+
+    /// Emits the events received during the last poll, with their original
+    /// timestamps, followed by any events needed to catch up after an
+    /// overflow; `timestamp` is ignored.
+    fn for_each_event<F>(&self, timestamp: Instant, mut f: F)
+    where
+        F: FnMut(KeyEvent),
+    {
+        let _ = timestamp;
+        self.events.iter().flatten().copied().for_each(&mut f);
+        if let Some((queued, snapshot_at)) = &self.resync {
+            for (row, queued) in queued.iter().enumerate() {
+                for col in 0..COLS {
+                    let pressed = self.pressed[row].get(col);
+                    if pressed != queued.get(col) {
+                        f(KeyEvent {
+                            row,
+                            col,
+                            action: if pressed {
+                                KeyAction::Pressed
+                            } else {
+                                KeyAction::Released
+                            },
+                            timestamp: *snapshot_at,
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    use std::vec::Vec;
+
+    /// A scanner with a single key, which is pressed by the test.
+    #[derive(Default)]
+    struct FakeScanner {
+        pressed: bool,
+        last_pressed: bool,
+        next: bool,
+    }
+
+    impl Scanner<1, 1> for FakeScanner {
+        type Error = Infallible;
+
+        fn poll(&mut self, now: Instant) -> Result<(), Self::Error> {
+            let _ = now;
+            self.last_pressed = self.pressed;
+            self.pressed = self.next;
+            Ok(())
+        }
+
+        fn is_pressed(&self, _row: usize, _col: usize) -> bool {
+            self.pressed
+        }
+
+        fn just_pressed(&self, _row: usize, _col: usize) -> bool {
+            self.pressed && !self.last_pressed
+        }
+
+        fn just_released(&self, _row: usize, _col: usize) -> bool {
+            !self.pressed && self.last_pressed
+        }
+    }
+
+    fn events<const N: usize>(receiver: &Receiver<1, 1, N>) -> Vec<(KeyAction, u32)> {
+        let mut events = Vec::new();
+        receiver.for_each_event(Instant::from_ticks(0), |event| {
+            events.push((event.action, event.timestamp.ticks()));
+        });
+        events
+    }
+
+    #[test]
+    fn events_keep_timestamps() {
+        let queue = EventQueue::new();
+        let mut background = Background::new(FakeScanner::default(), &queue);
+        let mut receiver = Receiver::<1, 1, 2>::new(&queue);
+
+        background.scanner().next = true;
+        background.scan(Instant::from_ticks(1)).unwrap();
+        background.scan(Instant::from_ticks(2)).unwrap();
+        background.scanner().next = false;
+        background.scan(Instant::from_ticks(3)).unwrap();
+
+        receiver.poll(Instant::from_ticks(10)).unwrap();
+        assert!(events(&receiver) == [(KeyAction::Pressed, 1), (KeyAction::Released, 3)]);
+        assert!(!receiver.is_pressed(0, 0));
+
+        receiver.poll(Instant::from_ticks(20)).unwrap();
+        assert!(events(&receiver).is_empty());
+    }
+
+    #[test]
+    fn overflow() {
+        let queue = EventQueue::new();
+        let mut background = Background::new(FakeScanner::default(), &queue);
+        let mut receiver = Receiver::<1, 1, 2>::new(&queue);
+
+        for ticks in 0..3 {
+            background.scanner().next = ticks % 2 == 0;
+            background.scan(Instant::from_ticks(ticks)).unwrap();
+        }
+
+        // The events that were queued are emitted, and then the press that
+        // was dropped is caught up with at the time of the last scan.
+        receiver.poll(Instant::from_ticks(10)).unwrap();
+        assert!(
+            events(&receiver)
+                == [
+                    (KeyAction::Pressed, 0),
+                    (KeyAction::Released, 1),
+                    (KeyAction::Pressed, 2)
+                ]
+        );
+        assert!(receiver.is_pressed(0, 0));
+        assert!(receiver.just_pressed(0, 0));
+
+        // The release isn't lost either.
+        background.scanner().next = false;
+        background.scan(Instant::from_ticks(4)).unwrap();
+        receiver.poll(Instant::from_ticks(20)).unwrap();
+        assert!(events(&receiver) == [(KeyAction::Released, 4)]);
+        assert!(!receiver.is_pressed(0, 0));
+    }
+
+    #[test]
+    fn dropped_release() {
+        let queue = EventQueue::new();
+        let mut background = Background::new(FakeScanner::default(), &queue);
+        let mut receiver = Receiver::<1, 1, 1>::new(&queue);
+
+        background.scanner().next = true;
+        background.scan(Instant::from_ticks(1)).unwrap();
+        background.scanner().next = false;
+        background.scan(Instant::from_ticks(2)).unwrap();
+
+        // The key doesn't stay pressed.
+        receiver.poll(Instant::from_ticks(10)).unwrap();
+        assert!(events(&receiver) == [(KeyAction::Pressed, 1), (KeyAction::Released, 2)]);
+        assert!(!receiver.is_pressed(0, 0));
+    }
+}
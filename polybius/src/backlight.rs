@@ -1,3 +1,5 @@
+use crate::time::Instant;
+
 /// Backlight interface for keyboard hardware.
 ///
 /// For keyboards that do not support backlight, the type [`NoBacklight`]
@@ -41,6 +43,12 @@ pub trait Backlight {
     fn cycle_step(&mut self) {
         self.set_level((self.level() + 1) % self.num_levels());
     }
+
+    /// Called periodically with the current time, to allow the backlight to
+    /// implement effects that change over time.
+    fn poll(&mut self, now: Instant) {
+        let _ = now;
+    }
 }
 
 /// A no-op backlight implementation that can be used by keyboards that do not
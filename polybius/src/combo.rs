@@ -0,0 +1,343 @@
+//! Keys that send a different keycode when pressed together.
+//!
+//! A [`Combo`] is a set of keys, given by their positions or their keycodes,
+//! that sends its own keycode when all of them are pressed within the combo
+//! term, instead of their own keycodes. Combos are matched by the
+//! [`tap_hold`](crate::tap_hold) engine before the keys are looked up, and
+//! each layer of a [`Layered`](crate::keymap::Layered) keymap can have its
+//! own combos.
+//!
+//! # Example
+//!
+//! Pressing J and K together sends Escape, on the base layer:
+//!
+//! ```ignore
+//! static LAYERS: [[[Keycode; 12]; 4]; 2] = [/* ... */];
+//!
+//! struct MyCombos;
+//!
+//! impl Combos<4, 12> for MyCombos {
+//!     const LAYERS: &'static [[[Keycode; 12]; 4]] = &LAYERS;
+//!     const COMBOS: &'static [&'static [Combo]] = &[&[Combo {
+//!         keys: &[ComboKey::Keycode(KC_J), ComboKey::Keycode(KC_K)],
+//!         keycode: KC_ESC,
+//!     }]];
+//! }
+//!
+//! let keymap = Layered::new(&LAYERS).with_combos(ComboTable::new::<MyCombos>());
+//! ```
+
+use core::marker::PhantomData;
+
+use crate::keycode::Keycode;
+use crate::keymap::Keymap;
+use crate::scanner::KeyEvent;
+use crate::time::{elapsed, Duration, Instant};
+
+/// A key of a [`Combo`].
+#[derive(Clone, Copy)]
+pub enum ComboKey {
+    /// The key at the given row and column.
+    Position(usize, usize),
+    /// Any key with the given keycode on the current layers.
+    Keycode(Keycode),
+}
+
+impl ComboKey {
+    fn matches<K, const ROWS: usize, const COLS: usize>(&self, event: &KeyEvent, keymap: &K) -> bool
+    where
+        K: Keymap<ROWS, COLS>,
+    {
+        match *self {
+            ComboKey::Position(row, col) => event.row == row && event.col == col,
+            ComboKey::Keycode(keycode) => keymap.get(event.row, event.col) == keycode,
+        }
+    }
+}
+
+/// Keys that send `keycode` when pressed together.
+#[derive(Clone, Copy)]
+pub struct Combo {
+    pub keys: &'static [ComboKey],
+    pub keycode: Keycode,
+}
+
+impl Combo {
+    /// Whether each of `presses` is a different key of this combo, so that
+    /// pressing the rest of them would complete it.
+    pub(crate) fn is_partial<K, const ROWS: usize, const COLS: usize>(
+        &self,
+        presses: &[Option<KeyEvent>],
+        keymap: &K,
+    ) -> bool
+    where
+        K: Keymap<ROWS, COLS>,
+    {
+        presses.len() <= self.keys.len()
+            && presses
+                .iter()
+                .flatten()
+                .all(|event| self.keys.iter().any(|key| key.matches(event, keymap)))
+    }
+
+    /// Whether `presses` are exactly the keys of this combo.
+    pub(crate) fn is_complete<K, const ROWS: usize, const COLS: usize>(
+        &self,
+        presses: &[Option<KeyEvent>],
+        keymap: &K,
+    ) -> bool
+    where
+        K: Keymap<ROWS, COLS>,
+    {
+        presses.len() == self.keys.len()
+            && self.is_partial(presses, keymap)
+            && self.keys.iter().all(|key| {
+                presses
+                    .iter()
+                    .flatten()
+                    .any(|event| key.matches(event, keymap))
+            })
+    }
+}
+
+/// The combos of each layer of a `ROWS` by `COLS` keymap, for
+/// [`ComboTable::new`].
+pub trait Combos<const ROWS: usize, const COLS: usize> {
+    /// The layers of the keymap.
+    const LAYERS: &'static [[[Keycode; COLS]; ROWS]];
+
+    /// The combos of each layer, starting from layer 0.
+    ///
+    /// Every combo must have at least two keys, its positions must be in the
+    /// keymap, and its keycodes must be on its layer or the layers below.
+    const COMBOS: &'static [&'static [Combo]];
+}
+
+/// The combos of a [`Combos`] implementation, checked at compile time.
+struct CheckedCombos<T, const ROWS: usize, const COLS: usize> {
+    _combos: PhantomData<T>,
+}
+
+impl<T, const ROWS: usize, const COLS: usize> CheckedCombos<T, ROWS, COLS>
+where
+    T: Combos<ROWS, COLS>,
+{
+    const COMBOS: &'static [&'static [Combo]] = {
+        check_combos(T::LAYERS, T::COMBOS);
+        T::COMBOS
+    };
+}
+
+/// The combos of each layer of a `ROWS` by `COLS` keymap, starting from layer
+/// 0, which have been checked against its layers.
+#[derive(Clone, Copy)]
+pub struct ComboTable<const ROWS: usize, const COLS: usize> {
+    combos: &'static [&'static [Combo]],
+}
+
+impl<const ROWS: usize, const COLS: usize> ComboTable<ROWS, COLS> {
+    /// A table without any combos.
+    pub const EMPTY: Self = Self { combos: &[] };
+
+    /// Creates a table of the combos of `T`, which are checked against its
+    /// layers at compile time, so that a mistake fails the build:
+    ///
+    /// ```compile_fail
+    /// use polybius::combo::{Combo, ComboKey, ComboTable, Combos};
+    /// use polybius::keycode::qmk::{KC_A, KC_B, KC_ESC};
+    /// use polybius::keycode::Keycode;
+    ///
+    /// struct MyCombos;
+    ///
+    /// impl Combos<1, 2> for MyCombos {
+    ///     const LAYERS: &'static [[[Keycode; 2]; 1]] = &[[[KC_A, KC_B]]];
+    ///     // There is no row 1.
+    ///     const COMBOS: &'static [&'static [Combo]] = &[&[Combo {
+    ///         keys: &[ComboKey::Position(0, 0), ComboKey::Position(1, 0)],
+    ///         keycode: KC_ESC,
+    ///     }]];
+    /// }
+    ///
+    /// let combos = ComboTable::new::<MyCombos>();
+    /// ```
+    pub const fn new<T>() -> Self
+    where
+        T: Combos<ROWS, COLS>,
+    {
+        Self {
+            combos: CheckedCombos::<T, ROWS, COLS>::COMBOS,
+        }
+    }
+
+    /// The combos of `layer`, if it has any.
+    pub(crate) fn get(&self, layer: usize) -> Option<&'static [Combo]> {
+        self.combos.get(layer).copied()
+    }
+}
+
+const fn check_combos<const ROWS: usize, const COLS: usize>(
+    layers: &[[[Keycode; COLS]; ROWS]],
+    combos: &[&[Combo]],
+) {
+    assert!(
+        combos.len() <= layers.len(),
+        "combos for a layer that doesn't exist"
+    );
+    let mut layer = 0;
+    while layer < combos.len() {
+        let mut i = 0;
+        while i < combos[layer].len() {
+            let keys = combos[layer][i].keys;
+            assert!(keys.len() >= 2, "combo with less than two keys");
+            let mut j = 0;
+            while j < keys.len() {
+                match keys[j] {
+                    ComboKey::Position(row, col) => {
+                        assert!(row < ROWS && col < COLS, "combo key position out of range");
+                    }
+                    ComboKey::Keycode(keycode) => {
+                        assert!(
+                            has_keycode(layers, layer, keycode),
+                            "combo keycode isn't on its layer"
+                        );
+                    }
+                }
+                j += 1;
+            }
+            i += 1;
+        }
+        layer += 1;
+    }
+}
+
+/// The result of matching presses against the active combos.
+pub(crate) enum ComboMatch {
+    /// The presses so far could still complete a combo.
+    Wait,
+    /// A combo with the given keycode matches the given number of presses.
+    Found(Keycode, usize),
+    None,
+}
+
+/// Matches the presses at the front of `buffer` against `combos`.
+///
+/// Only presses within `term` of the first one can be part of a combo, and
+/// they only wait for the rest of a combo until `term` has passed, or while
+/// `buffer` has room for more.
+pub(crate) fn match_combo<K, const ROWS: usize, const COLS: usize>(
+    combos: &[Combo],
+    buffer: &[Option<KeyEvent>],
+    now: Instant,
+    term: Duration,
+    keymap: &K,
+) -> ComboMatch
+where
+    K: Keymap<ROWS, COLS>,
+{
+    let Some(Some(first)) = buffer.first() else {
+        return ComboMatch::None;
+    };
+    // The number of presses at the front that could be part of a combo.
+    let mut len = 0;
+    while let Some(Some(event)) = buffer.get(len) {
+        let candidate = event.action.is_pressed()
+            && elapsed(first.timestamp, event.timestamp) < term
+            && combos
+                .iter()
+                .any(|combo| combo.is_partial(&buffer[..=len], keymap));
+        if !candidate {
+            break;
+        }
+        len += 1;
+    }
+    if len == 0 {
+        return ComboMatch::None;
+    }
+    // A full buffer can't wait for more presses.
+    let waiting = buffer.get(len).is_some_and(Option::is_none);
+    if waiting && elapsed(first.timestamp, now) < term {
+        let incomplete = combos
+            .iter()
+            .any(|combo| combo.keys.len() > len && combo.is_partial(&buffer[..len], keymap));
+        if incomplete {
+            return ComboMatch::Wait;
+        }
+    }
+    combos
+        .iter()
+        .filter(|combo| combo.keys.len() <= len)
+        .filter(|combo| combo.is_complete(&buffer[..combo.keys.len()], keymap))
+        .max_by_key(|combo| combo.keys.len())
+        .map_or(ComboMatch::None, |combo| {
+            ComboMatch::Found(combo.keycode, combo.keys.len())
+        })
+}
+
+/// Whether `keycode` is on any layer up to `top`.
+const fn has_keycode<const ROWS: usize, const COLS: usize>(
+    layers: &[[[Keycode; COLS]; ROWS]],
+    top: usize,
+    keycode: Keycode,
+) -> bool {
+    let mut layer = 0;
+    while layer <= top {
+        let mut row = 0;
+        while row < ROWS {
+            let mut col = 0;
+            while col < COLS {
+                if layers[layer][row][col].const_eq(&keycode) {
+                    return true;
+                }
+                col += 1;
+            }
+            row += 1;
+        }
+        layer += 1;
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keycode::qmk::{KC_A, KC_B, KC_C, KC_ESC, KC_TRNS};
+
+    const LAYERS: [[[Keycode; 2]; 1]; 2] = [[[KC_A, KC_B]], [[KC_C, KC_TRNS]]];
+
+    const fn combo(keys: &'static [ComboKey]) -> Combo {
+        Combo {
+            keys,
+            keycode: KC_ESC,
+        }
+    }
+
+    struct Valid;
+
+    impl Combos<1, 2> for Valid {
+        const LAYERS: &'static [[[Keycode; 2]; 1]] = &LAYERS;
+        const COMBOS: &'static [&'static [Combo]] = &[
+            &[combo(&[ComboKey::Keycode(KC_A), ComboKey::Position(0, 1)])],
+            &[combo(&[ComboKey::Keycode(KC_C), ComboKey::Keycode(KC_B)])],
+        ];
+    }
+
+    const _: ComboTable<1, 2> = ComboTable::new::<Valid>();
+
+    #[test]
+    #[should_panic(expected = "combo keycode isn't on its layer")]
+    fn keycode_on_higher_layer() {
+        check_combos(
+            &LAYERS,
+            &[&[combo(&[ComboKey::Keycode(KC_A), ComboKey::Keycode(KC_C)])]],
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "combo key position out of range")]
+    fn position_out_of_range() {
+        check_combos(
+            &LAYERS,
+            &[&[combo(&[ComboKey::Keycode(KC_A), ComboKey::Position(1, 0)])]],
+        );
+    }
+}
@@ -0,0 +1,271 @@
+//! Combining several scanners into one.
+//!
+//! Some keyboards scan their keys from several sources, like a matrix for the
+//! main keys and a few direct pins for the thumb keys, or a local half and a
+//! remote half. Each source can be placed in the keyboard's logical grid with
+//! an [`Offset`], and then the sources can be merged with [`Composite`], so
+//! that the rest of the firmware sees a single [`Scanner`].
+//!
+//! # Example
+//!
+//! A 5x12 keyboard, with a 4x12 matrix for the main keys, and 4 thumb keys in
+//! the middle of the bottom row:
+//!
+//! ```ignore
+//! type Main = ScanMatrix<WriteLines, ReadLines, ScanDelay, ColToRow, NoDebounce, 4, 12>;
+//! type Thumbs = DirectPins<ThumbPins, ActiveLow, RowMajor, NoDebounce, 1, 4>;
+//! type Scanner = Composite<Offset<Main, 4, 12, 0, 0>, Offset<Thumbs, 1, 4, 4, 4>>;
+//!
+//! let scanner: Scanner = Composite(Offset(main), Offset(thumbs));
+//! ```
+
+use crate::scanner::{KeyEvent, Scanner};
+use crate::time::Instant;
+
+/// A scanner of `R` rows and `C` columns, placed at row `ROW` and column `COL`
+/// of a larger grid.
+///
+/// The wrapped scanner can be used as a scanner of any grid size that it fits
+/// in. Keys outside of the area covered by the wrapped scanner are never
+/// pressed.
+pub struct Offset<S, const R: usize, const C: usize, const ROW: usize, const COL: usize>(pub S);
+
+impl<S, const R: usize, const C: usize, const ROW: usize, const COL: usize>
+    Offset<S, R, C, ROW, COL>
+{
+    /// Maps a position in the larger grid to a position of the wrapped
+    /// scanner, if it covers that position.
+    fn inner_position(row: usize, col: usize) -> Option<(usize, usize)> {
+        let row = row.checked_sub(ROW).filter(|&row| row < R)?;
+        let col = col.checked_sub(COL).filter(|&col| col < C)?;
+        Some((row, col))
+    }
+}
+
+/// Checks that an [`Offset`] fits in a `ROWS` by `COLS` grid.
+struct Fits<
+    const R: usize,
+    const C: usize,
+    const ROW: usize,
+    const COL: usize,
+    const ROWS: usize,
+    const COLS: usize,
+>;
+
+impl<
+        const R: usize,
+        const C: usize,
+        const ROW: usize,
+        const COL: usize,
+        const ROWS: usize,
+        const COLS: usize,
+    > Fits<R, C, ROW, COL, ROWS, COLS>
+{
+    const CHECK: () = assert!(
+        ROW + R <= ROWS && COL + C <= COLS,
+        "offset scanner does not fit in the grid"
+    );
+}
+
+impl<
+        S,
+        const R: usize,
+        const C: usize,
+        const ROW: usize,
+        const COL: usize,
+        const ROWS: usize,
+        const COLS: usize,
+    > Scanner<ROWS, COLS> for Offset<S, R, C, ROW, COL>
+where
+    S: Scanner<R, C>,
+{
+    type Error = S::Error;
+
+    fn poll(&mut self, now: Instant) -> Result<(), Self::Error> {
+        let () = Fits::<R, C, ROW, COL, ROWS, COLS>::CHECK;
+        self.0.poll(now)
+    }
+
+    fn is_pressed(&self, row: usize, col: usize) -> bool {
+        Self::inner_position(row, col).is_some_and(|(row, col)| self.0.is_pressed(row, col))
+    }
+
+    fn just_pressed(&self, row: usize, col: usize) -> bool {
+        Self::inner_position(row, col).is_some_and(|(row, col)| self.0.just_pressed(row, col))
+    }
+
+    fn just_released(&self, row: usize, col: usize) -> bool {
+        Self::inner_position(row, col).is_some_and(|(row, col)| self.0.just_released(row, col))
+    }
+
+    fn ghosted(&self) -> bool {
+        self.0.ghosted()
+    }
+
+    fn prepare_wake(&mut self) -> Result<bool, Self::Error> {
+        self.0.prepare_wake()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.is_idle()
+    }
+
+    fn for_each_event<F>(&self, timestamp: Instant, mut f: F)
+    where
+        F: FnMut(KeyEvent),
+    {
+        self.0.for_each_event(timestamp, |event| {
+            f(KeyEvent {
+                row: event.row + ROW,
+                col: event.col + COL,
+                ..event
+            })
+        })
+    }
+}
+
+/// Two scanners of the same grid, merged into one.
+///
+/// A key is pressed if it is pressed in either scanner; normally, each key is
+/// only covered by one of them, by wrapping each of them in an [`Offset`].
+/// More than two scanners can be merged by nesting composites.
+pub struct Composite<A, B>(pub A, pub B);
+
+/// An error from one of the scanners in a [`Composite`].
+#[derive(Debug)]
+pub enum CompositeError<A, B> {
+    First(A),
+    Second(B),
+}
+
+impl<A, B, const ROWS: usize, const COLS: usize> Scanner<ROWS, COLS> for Composite<A, B>
+where
+    A: Scanner<ROWS, COLS>,
+    B: Scanner<ROWS, COLS>,
+{
+    type Error = CompositeError<A::Error, B::Error>;
+
+    fn poll(&mut self, now: Instant) -> Result<(), Self::Error> {
+        self.0.poll(now).map_err(CompositeError::First)?;
+        self.1.poll(now).map_err(CompositeError::Second)?;
+        Ok(())
+    }
+
+    fn is_pressed(&self, row: usize, col: usize) -> bool {
+        self.0.is_pressed(row, col) || self.1.is_pressed(row, col)
+    }
+
+    fn just_pressed(&self, row: usize, col: usize) -> bool {
+        self.0.just_pressed(row, col) || self.1.just_pressed(row, col)
+    }
+
+    fn just_released(&self, row: usize, col: usize) -> bool {
+        self.0.just_released(row, col) || self.1.just_released(row, col)
+    }
+
+    fn ghosted(&self) -> bool {
+        self.0.ghosted() || self.1.ghosted()
+    }
+
+    /// Only supported if both scanners support it.
+    fn prepare_wake(&mut self) -> Result<bool, Self::Error> {
+        let first = self.0.prepare_wake().map_err(CompositeError::First)?;
+        let second = self.1.prepare_wake().map_err(CompositeError::Second)?;
+        Ok(first && second)
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.is_idle() && self.1.is_idle()
+    }
+
+    /// Emits the events of the first scanner, then those of the second one.
+    fn for_each_event<F>(&self, timestamp: Instant, mut f: F)
+    where
+        F: FnMut(KeyEvent),
+    {
+        self.0.for_each_event(timestamp, &mut f);
+        self.1.for_each_event(timestamp, f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A scanner reporting a fixed set of pressed keys, or failing to poll.
+    struct FakeScanner<const R: usize, const C: usize> {
+        pressed: [[bool; C]; R],
+        error: Option<u8>,
+    }
+
+    impl<const R: usize, const C: usize> Scanner<R, C> for FakeScanner<R, C> {
+        type Error = u8;
+
+        fn poll(&mut self, now: Instant) -> Result<(), Self::Error> {
+            let _ = now;
+            self.error.map_or(Ok(()), Err)
+        }
+
+        fn is_pressed(&self, row: usize, col: usize) -> bool {
+            self.pressed[row][col]
+        }
+
+        fn just_pressed(&self, row: usize, col: usize) -> bool {
+            self.pressed[row][col]
+        }
+
+        fn just_released(&self, row: usize, col: usize) -> bool {
+            let _ = (row, col);
+            false
+        }
+    }
+
+    type Merged =
+        Composite<Offset<FakeScanner<2, 2>, 2, 2, 0, 0>, Offset<FakeScanner<1, 2>, 1, 2, 2, 1>>;
+
+    fn merged(main_error: Option<u8>, thumb_error: Option<u8>) -> Merged {
+        Composite(
+            Offset(FakeScanner {
+                pressed: [[true, false], [false, true]],
+                error: main_error,
+            }),
+            Offset(FakeScanner {
+                pressed: [[false, true]],
+                error: thumb_error,
+            }),
+        )
+    }
+
+    #[test]
+    fn offsets() {
+        let mut scanner = merged(None, None);
+        Scanner::<3, 3>::poll(&mut scanner, Instant::from_ticks(0)).unwrap();
+
+        let keys = [(0, 0), (1, 1), (2, 2)];
+        for row in 0..3 {
+            for col in 0..3 {
+                let expected = keys.contains(&(row, col));
+                assert_eq!(
+                    Scanner::<3, 3>::is_pressed(&scanner, row, col),
+                    expected,
+                    "({row}, {col})"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let mut scanner = merged(Some(1), Some(2));
+        assert!(matches!(
+            Scanner::<3, 3>::poll(&mut scanner, Instant::from_ticks(0)),
+            Err(CompositeError::First(1))
+        ));
+
+        let mut scanner = merged(None, Some(2));
+        assert!(matches!(
+            Scanner::<3, 3>::poll(&mut scanner, Instant::from_ticks(0)),
+            Err(CompositeError::Second(2))
+        ));
+    }
+}
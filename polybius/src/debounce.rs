@@ -0,0 +1,320 @@
+//! Switch debouncing.
+//!
+//! Mechanical switches do not make or break contact cleanly; for a few
+//! milliseconds after being pressed or released, the contacts "bounce" and the
+//! raw state read from the matrix may flip back and forth several times. A
+//! [`Debouncer`] filters the raw state read by the scanner, so that only real
+//! presses and releases are reported as key events.
+//!
+//! All of the debouncers provided here are configured with a debounce time,
+//! and are given the time of each scan, so their behavior does not depend on
+//! how often the matrix is scanned.
+
+use crate::scanner::{row_bytes, ScanRow};
+use crate::time::{has_elapsed, Duration, Instant};
+
+/// Filters raw key states into debounced key states.
+pub trait Debouncer<const ROWS: usize, const COLS: usize>
+where
+    [(); row_bytes(COLS)]:,
+{
+    /// Updates the debounced key state `state` given the most recent raw key
+    /// state `raw`, read from the matrix at time `now`.
+    fn debounce(
+        &mut self,
+        now: Instant,
+        raw: &[ScanRow<COLS>; ROWS],
+        state: &mut [ScanRow<COLS>; ROWS],
+    );
+}
+
+/// A no-op debouncer that passes the raw key state through unchanged.
+///
+/// This can be used by keyboards whose switches do not bounce, or whose
+/// matrix is already debounced in hardware.
+pub struct NoDebounce;
+
+impl<const ROWS: usize, const COLS: usize> Debouncer<ROWS, COLS> for NoDebounce
+where
+    [(); row_bytes(COLS)]:,
+{
+    fn debounce(
+        &mut self,
+        now: Instant,
+        raw: &[ScanRow<COLS>; ROWS],
+        state: &mut [ScanRow<COLS>; ROWS],
+    ) {
+        let _ = now;
+        *state = *raw;
+    }
+}
+
+/// Eager per-key debouncing.
+///
+/// A change in a key's raw state is reported immediately, and then any further
+/// changes to that key are ignored until the debounce time has elapsed. This
+/// has the lowest latency, but is susceptible to electrical noise, since a
+/// single spurious reading will be reported as a key event.
+pub struct EagerPerKey<const ROWS: usize, const COLS: usize>
+where
+    [(); row_bytes(COLS)]:,
+{
+    delay: Duration,
+    locked: [ScanRow<COLS>; ROWS],
+    changed_at: [[Instant; COLS]; ROWS],
+}
+
+impl<const ROWS: usize, const COLS: usize> EagerPerKey<ROWS, COLS>
+where
+    [(); row_bytes(COLS)]:,
+{
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            locked: [ScanRow::EMPTY; ROWS],
+            changed_at: [[Instant::from_ticks(0); COLS]; ROWS],
+        }
+    }
+}
+
+impl<const ROWS: usize, const COLS: usize> Debouncer<ROWS, COLS> for EagerPerKey<ROWS, COLS>
+where
+    [(); row_bytes(COLS)]:,
+{
+    fn debounce(
+        &mut self,
+        now: Instant,
+        raw: &[ScanRow<COLS>; ROWS],
+        state: &mut [ScanRow<COLS>; ROWS],
+    ) {
+        for row in 0..ROWS {
+            for col in 0..COLS {
+                if self.locked[row].get(col)
+                    && has_elapsed(now, self.changed_at[row][col], self.delay)
+                {
+                    self.locked[row].set(col, false);
+                }
+            }
+
+            let changed = (raw[row] ^ state[row]) & !self.locked[row];
+            for col in 0..COLS {
+                if changed.get(col) {
+                    self.changed_at[row][col] = now;
+                }
+            }
+            state[row] ^= changed;
+            self.locked[row] |= changed;
+        }
+    }
+}
+
+/// Deferred per-key debouncing.
+///
+/// A change in a key's raw state is only reported once that key has stayed in
+/// the new state for the whole debounce time. This adds latency equal to the
+/// debounce time, but is immune to short noise spikes.
+pub struct DeferredPerKey<const ROWS: usize, const COLS: usize>
+where
+    [(); row_bytes(COLS)]:,
+{
+    delay: Duration,
+    last_raw: [ScanRow<COLS>; ROWS],
+    changed_at: [[Instant; COLS]; ROWS],
+}
+
+impl<const ROWS: usize, const COLS: usize> DeferredPerKey<ROWS, COLS>
+where
+    [(); row_bytes(COLS)]:,
+{
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            last_raw: [ScanRow::EMPTY; ROWS],
+            changed_at: [[Instant::from_ticks(0); COLS]; ROWS],
+        }
+    }
+}
+
+impl<const ROWS: usize, const COLS: usize> Debouncer<ROWS, COLS> for DeferredPerKey<ROWS, COLS>
+where
+    [(); row_bytes(COLS)]:,
+{
+    fn debounce(
+        &mut self,
+        now: Instant,
+        raw: &[ScanRow<COLS>; ROWS],
+        state: &mut [ScanRow<COLS>; ROWS],
+    ) {
+        for row in 0..ROWS {
+            let bounced = raw[row] ^ self.last_raw[row];
+            self.last_raw[row] = raw[row];
+
+            let pending = raw[row] ^ state[row];
+            for col in 0..COLS {
+                if bounced.get(col) {
+                    self.changed_at[row][col] = now;
+                }
+                if pending.get(col) && has_elapsed(now, self.changed_at[row][col], self.delay) {
+                    state[row].toggle(col);
+                }
+            }
+        }
+    }
+}
+
+/// Symmetric deferred per-row debouncing.
+///
+/// Like [`DeferredPerKey`], but the debounce timer is shared by all keys in a
+/// row: any change in the row restarts the timer, and the whole row is updated
+/// at once when it expires. This needs much less memory than per-key
+/// debouncing, at the cost of occasionally delaying a key event while another
+/// key in the same row is bouncing.
+pub struct SymmetricPerRow<const ROWS: usize, const COLS: usize>
+where
+    [(); row_bytes(COLS)]:,
+{
+    delay: Duration,
+    last_raw: [ScanRow<COLS>; ROWS],
+    changed_at: [Instant; ROWS],
+}
+
+impl<const ROWS: usize, const COLS: usize> SymmetricPerRow<ROWS, COLS>
+where
+    [(); row_bytes(COLS)]:,
+{
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            last_raw: [ScanRow::EMPTY; ROWS],
+            changed_at: [Instant::from_ticks(0); ROWS],
+        }
+    }
+}
+
+impl<const ROWS: usize, const COLS: usize> Debouncer<ROWS, COLS> for SymmetricPerRow<ROWS, COLS>
+where
+    [(); row_bytes(COLS)]:,
+{
+    fn debounce(
+        &mut self,
+        now: Instant,
+        raw: &[ScanRow<COLS>; ROWS],
+        state: &mut [ScanRow<COLS>; ROWS],
+    ) {
+        for row in 0..ROWS {
+            if raw[row] != self.last_raw[row] {
+                self.last_raw[row] = raw[row];
+                self.changed_at[row] = now;
+            }
+            if raw[row] != state[row] && has_elapsed(now, self.changed_at[row], self.delay) {
+                state[row] = raw[row];
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::diodes::ColToRow;
+    use crate::scanner::{Direct, ScanMatrix, Scanner};
+
+    use embedded_hal_mock::pin::{Mock, State, Transaction};
+    use std::string::String;
+    use std::vec::Vec;
+
+    /// Scans a 1x2 matrix once per millisecond, with each switch following the
+    /// given pattern of raw states (`#` closed, `_` open). An empty pattern
+    /// leaves that switch open for the whole run.
+    ///
+    /// Returns the debounced edges reported after each scan for each switch
+    /// (`P` just pressed, `R` just released, `-` no change).
+    fn run<B>(debouncer: B, patterns: [&str; 2]) -> [String; 2]
+    where
+        B: Debouncer<1, 2>,
+    {
+        let scans = patterns[0].len().max(patterns[1].len());
+
+        let mut write_pin = Mock::new(
+            &(0..scans)
+                .flat_map(|_| [Transaction::set(State::High), Transaction::set(State::Low)])
+                .collect::<Vec<_>>(),
+        );
+        let mut read_pins = patterns.map(|pattern| {
+            Mock::new(
+                &(0..scans)
+                    .map(|t| match pattern.as_bytes().get(t) {
+                        Some(b'#') => Transaction::get(State::Low),
+                        _ => Transaction::get(State::High),
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        });
+
+        let mut matrix: ScanMatrix<_, _, _, ColToRow, _, 1, 2> = ScanMatrix::new(
+            Direct([write_pin.clone()]),
+            Direct(read_pins.clone()),
+            || {},
+            debouncer,
+        );
+
+        let mut edges = [String::new(), String::new()];
+        for t in 0..scans {
+            matrix.poll(Instant::from_ticks(t as u32)).unwrap();
+            for (col, edges) in edges.iter_mut().enumerate() {
+                edges.push(if matrix.just_pressed(0, col) {
+                    'P'
+                } else if matrix.just_released(0, col) {
+                    'R'
+                } else {
+                    '-'
+                });
+            }
+        }
+
+        write_pin.done();
+        for pin in &mut read_pins {
+            pin.done();
+        }
+        edges
+    }
+
+    #[test]
+    fn no_debounce() {
+        let [edges, _] = run(NoDebounce, ["__#_##___", ""]);
+        assert_eq!(edges, "--PRP-R--");
+    }
+
+    #[test]
+    fn eager_per_key() {
+        let debouncer = EagerPerKey::new(Duration::millis(5));
+        let [edges, _] = run(debouncer, ["__#_#_#####_#_____#_", ""]);
+        assert_eq!(edges, "--P--------R------P-");
+    }
+
+    #[test]
+    fn deferred_per_key() {
+        let debouncer = DeferredPerKey::new(Duration::millis(5));
+        let [edges, _] = run(debouncer, ["__#_#_#########_#___________", ""]);
+        assert_eq!(edges, "-----------P----------R-----");
+    }
+
+    #[test]
+    fn deferred_per_key_ignores_noise() {
+        let debouncer = DeferredPerKey::new(Duration::millis(5));
+        let [edges, _] = run(debouncer, ["__#____#_#___#____", ""]);
+        assert_eq!(edges, "------------------");
+    }
+
+    #[test]
+    fn symmetric_per_row() {
+        let debouncer = SymmetricPerRow::new(Duration::millis(5));
+        let [a, b] = run(debouncer, ["_#################", "____#_#___________"]);
+        // Key B bouncing holds back the press of key A, which is otherwise
+        // stable, until the whole row has settled.
+        assert_eq!(a, "------------P-----");
+        assert_eq!(b, "------------------");
+    }
+}
@@ -0,0 +1,414 @@
+//! Matrix diagnostics.
+//!
+//! [`Diagnostics`] wraps a scanner and keeps statistics about each key, to help
+//! find faulty switches and wiring: how many times each key was pressed, how
+//! many of those presses were suspiciously short (chatter), and which keys have
+//! been held for suspiciously long (stuck or shorted). Bad keys can optionally
+//! be masked, so that they stop sending key events.
+
+use crate::diodes::{DiodeConfiguration, ScanPosition};
+use crate::keycode::KeyAction;
+use crate::scanner::{row_bytes, KeyEvent, ScanRow, Scanner};
+use crate::time::{has_elapsed, Duration, Instant};
+
+/// Thresholds for detecting bad keys.
+#[derive(Clone, Copy)]
+pub struct Limits {
+    /// Presses shorter than this are counted as chatter.
+    pub chatter_time: Duration,
+    /// Keys held for longer than this are considered stuck.
+    pub stuck_time: Duration,
+    /// Whether to mask keys that are stuck, or that have chattered at least
+    /// `max_chatters` times.
+    pub auto_mask: bool,
+    pub max_chatters: u16,
+}
+
+/// Statistics about a single key.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyReport {
+    pub presses: u16,
+    pub chatters: u16,
+    pub stuck: bool,
+    pub masked: bool,
+}
+
+/// Statistics about the whole matrix.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct Report {
+    pub presses: u32,
+    /// The number of keys that have chattered at least once.
+    pub chattering_keys: usize,
+    pub stuck_keys: usize,
+    pub masked_keys: usize,
+}
+
+/// A scanner that keeps statistics about the keys of another scanner, and
+/// masks bad keys.
+///
+/// Masked keys are reported as released; if a key is masked while it is held,
+/// a release event is emitted for it.
+pub struct Diagnostics<S, const ROWS: usize, const COLS: usize>
+where
+    [(); row_bytes(COLS)]:,
+{
+    scanner: S,
+    limits: Limits,
+    pressed: [ScanRow<COLS>; ROWS],
+    last_pressed: [ScanRow<COLS>; ROWS],
+    stuck: [ScanRow<COLS>; ROWS],
+    masked: [ScanRow<COLS>; ROWS],
+    pressed_at: [[Option<Instant>; COLS]; ROWS],
+    presses: [[u16; COLS]; ROWS],
+    chatters: [[u16; COLS]; ROWS],
+}
+
+impl<S, const ROWS: usize, const COLS: usize> Diagnostics<S, ROWS, COLS>
+where
+    S: Scanner<ROWS, COLS>,
+    [(); row_bytes(COLS)]:,
+{
+    pub fn new(scanner: S, limits: Limits) -> Self {
+        Self {
+            scanner,
+            limits,
+            pressed: [ScanRow::EMPTY; ROWS],
+            last_pressed: [ScanRow::EMPTY; ROWS],
+            stuck: [ScanRow::EMPTY; ROWS],
+            masked: [ScanRow::EMPTY; ROWS],
+            pressed_at: [[None; COLS]; ROWS],
+            presses: [[0; COLS]; ROWS],
+            chatters: [[0; COLS]; ROWS],
+        }
+    }
+
+    pub fn scanner(&mut self) -> &mut S {
+        &mut self.scanner
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn key_report(&self, row: usize, col: usize) -> KeyReport {
+        KeyReport {
+            presses: self.presses[row][col],
+            chatters: self.chatters[row][col],
+            stuck: self.stuck[row].get(col),
+            masked: self.masked[row].get(col),
+        }
+    }
+
+    pub fn report(&self) -> Report {
+        let mut report = Report::default();
+        for row in 0..ROWS {
+            for col in 0..COLS {
+                report.presses += u32::from(self.presses[row][col]);
+                if self.chatters[row][col] > 0 {
+                    report.chattering_keys += 1;
+                }
+            }
+            report.stuck_keys += self.stuck[row].count();
+            report.masked_keys += self.masked[row].count();
+        }
+        report
+    }
+
+    /// Whether every key on a read line of the electrical matrix is stuck,
+    /// which usually means the line itself is shorted.
+    pub fn read_line_failed<C>(&self, read_index: usize) -> bool
+    where
+        C: DiodeConfiguration<ROWS, COLS>,
+    {
+        let mut keys = (0..C::WRITE_LINES).filter_map(|write_index| {
+            C::key_position(ScanPosition {
+                write_index,
+                read_index,
+            })
+        });
+        let mut any = false;
+        keys.all(|pos| {
+            any = true;
+            self.stuck[pos.row].get(pos.col)
+        }) && any
+    }
+
+    /// Unmasks all keys, and clears all statistics.
+    pub fn reset(&mut self) {
+        self.stuck = [ScanRow::EMPTY; ROWS];
+        self.masked = [ScanRow::EMPTY; ROWS];
+        self.presses = [[0; COLS]; ROWS];
+        self.chatters = [[0; COLS]; ROWS];
+    }
+}
+
+impl<S, const ROWS: usize, const COLS: usize> Scanner<ROWS, COLS> for Diagnostics<S, ROWS, COLS>
+where
+    S: Scanner<ROWS, COLS>,
+    [(); row_bytes(COLS)]:,
+{
+    type Error = S::Error;
+
+    fn poll(&mut self, now: Instant) -> Result<(), Self::Error> {
+        self.scanner.poll(now)?;
+        let limits = self.limits;
+        for row in 0..ROWS {
+            for col in 0..COLS {
+                let is_pressed = self.scanner.is_pressed(row, col);
+                if self.scanner.just_pressed(row, col) {
+                    self.pressed_at[row][col] = Some(now);
+                    self.presses[row][col] = self.presses[row][col].saturating_add(1);
+                } else if is_pressed && self.pressed_at[row][col].is_none() {
+                    // Keys that were already held when they were first
+                    // scanned are measured from then.
+                    self.pressed_at[row][col] = Some(now);
+                }
+                let pressed_at = self.pressed_at[row][col];
+                if !is_pressed {
+                    self.pressed_at[row][col] = None;
+                }
+                let held = |time| pressed_at.is_some_and(|since| has_elapsed(now, since, time));
+                if self.scanner.just_released(row, col) {
+                    self.stuck[row].set(col, false);
+                    if !held(limits.chatter_time) {
+                        self.chatters[row][col] = self.chatters[row][col].saturating_add(1);
+                        if limits.auto_mask && self.chatters[row][col] >= limits.max_chatters {
+                            self.masked[row].set(col, true);
+                        }
+                    }
+                }
+                if is_pressed && held(limits.stuck_time) {
+                    self.stuck[row].set(col, true);
+                    if limits.auto_mask {
+                        self.masked[row].set(col, true);
+                    }
+                }
+
+                self.last_pressed[row].set(col, self.pressed[row].get(col));
+                self.pressed[row].set(
+                    col,
+                    self.scanner.is_pressed(row, col) && !self.masked[row].get(col),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn is_pressed(&self, row: usize, col: usize) -> bool {
+        self.pressed[row].get(col)
+    }
+
+    fn just_pressed(&self, row: usize, col: usize) -> bool {
+        (self.pressed[row] & !self.last_pressed[row]).get(col)
+    }
+
+    fn just_released(&self, row: usize, col: usize) -> bool {
+        (!self.pressed[row] & self.last_pressed[row]).get(col)
+    }
+
+    fn ghosted(&self) -> bool {
+        self.scanner.ghosted()
+    }
+
+    fn prepare_wake(&mut self) -> Result<bool, Self::Error> {
+        self.scanner.prepare_wake()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.scanner.is_idle()
+    }
+
+    /// Emits the events of the wrapped scanner that are not masked, in its
+    /// order, followed by the releases of keys that were just masked.
+    fn for_each_event<F>(&self, timestamp: Instant, mut f: F)
+    where
+        F: FnMut(KeyEvent),
+    {
+        let change = |row, col| {
+            if self.just_pressed(row, col) {
+                Some(KeyAction::Pressed)
+            } else if self.just_released(row, col) {
+                Some(KeyAction::Released)
+            } else {
+                None
+            }
+        };
+        self.scanner.for_each_event(timestamp, |event| {
+            if change(event.row, event.col) == Some(event.action) {
+                f(event);
+            }
+        });
+        for row in 0..ROWS {
+            for col in 0..COLS {
+                let inner_changed =
+                    self.scanner.just_pressed(row, col) || self.scanner.just_released(row, col);
+                if let (Some(action), false) = (change(row, col), inner_changed) {
+                    f(KeyEvent {
+                        row,
+                        col,
+                        action,
+                        timestamp,
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::diodes::ColToRow;
+
+    use core::convert::Infallible;
+    use std::vec::Vec;
+
+    /// A scanner whose keys are pressed by the test.
+    #[derive(Default)]
+    struct FakeScanner {
+        pressed: [[bool; 2]; 2],
+        last_pressed: [[bool; 2]; 2],
+        next: [[bool; 2]; 2],
+    }
+
+    impl Scanner<2, 2> for FakeScanner {
+        type Error = Infallible;
+
+        fn poll(&mut self, now: Instant) -> Result<(), Self::Error> {
+            let _ = now;
+            self.last_pressed = self.pressed;
+            self.pressed = self.next;
+            Ok(())
+        }
+
+        fn is_pressed(&self, row: usize, col: usize) -> bool {
+            self.pressed[row][col]
+        }
+
+        fn just_pressed(&self, row: usize, col: usize) -> bool {
+            self.pressed[row][col] && !self.last_pressed[row][col]
+        }
+
+        fn just_released(&self, row: usize, col: usize) -> bool {
+            !self.pressed[row][col] && self.last_pressed[row][col]
+        }
+    }
+
+    fn diagnostics(auto_mask: bool) -> Diagnostics<FakeScanner, 2, 2> {
+        Diagnostics::new(
+            FakeScanner::default(),
+            Limits {
+                chatter_time: Duration::millis(10),
+                stuck_time: Duration::millis(1000),
+                auto_mask,
+                max_chatters: 2,
+            },
+        )
+    }
+
+    /// Sets the state of key (0, 0), and polls at the given time.
+    fn poll(diagnostics: &mut Diagnostics<FakeScanner, 2, 2>, pressed: bool, ticks: u32) {
+        diagnostics.scanner().next[0][0] = pressed;
+        diagnostics.poll(Instant::from_ticks(ticks)).unwrap();
+    }
+
+    fn events(diagnostics: &Diagnostics<FakeScanner, 2, 2>) -> Vec<(usize, usize, KeyAction)> {
+        let mut events = Vec::new();
+        diagnostics.for_each_event(Instant::from_ticks(0), |event| {
+            events.push((event.row, event.col, event.action));
+        });
+        events
+    }
+
+    #[test]
+    fn presses_and_chatter() {
+        let mut diagnostics = diagnostics(false);
+        poll(&mut diagnostics, true, 0);
+        poll(&mut diagnostics, false, 50);
+        poll(&mut diagnostics, true, 100);
+        poll(&mut diagnostics, false, 105);
+
+        assert!(
+            diagnostics.key_report(0, 0)
+                == KeyReport {
+                    presses: 2,
+                    chatters: 1,
+                    stuck: false,
+                    masked: false,
+                }
+        );
+        assert!(diagnostics.report().presses == 2);
+        assert!(diagnostics.report().chattering_keys == 1);
+    }
+
+    #[test]
+    fn stuck_key_is_masked() {
+        let mut diagnostics = diagnostics(true);
+        poll(&mut diagnostics, true, 0);
+        assert!(events(&diagnostics) == [(0, 0, KeyAction::Pressed)]);
+
+        poll(&mut diagnostics, true, 999);
+        assert!(diagnostics.is_pressed(0, 0));
+        assert!(events(&diagnostics).is_empty());
+
+        poll(&mut diagnostics, true, 1000);
+        assert!(diagnostics.key_report(0, 0).stuck);
+        assert!(!diagnostics.is_pressed(0, 0));
+        assert!(events(&diagnostics) == [(0, 0, KeyAction::Released)]);
+
+        // The key recovers, but stays masked.
+        poll(&mut diagnostics, false, 1100);
+        poll(&mut diagnostics, true, 1200);
+        assert!(!diagnostics.key_report(0, 0).stuck);
+        assert!(!diagnostics.is_pressed(0, 0));
+        assert!(events(&diagnostics).is_empty());
+
+        diagnostics.reset();
+        poll(&mut diagnostics, true, 1300);
+        assert!(diagnostics.is_pressed(0, 0));
+        assert!(events(&diagnostics) == [(0, 0, KeyAction::Pressed)]);
+    }
+
+    #[test]
+    fn stuck_since_power_up() {
+        let mut diagnostics = diagnostics(false);
+        diagnostics.scanner().pressed[0][0] = true;
+        poll(&mut diagnostics, true, 5000);
+        assert!(!diagnostics.key_report(0, 0).stuck);
+
+        poll(&mut diagnostics, true, 5999);
+        assert!(!diagnostics.key_report(0, 0).stuck);
+
+        poll(&mut diagnostics, true, 6000);
+        assert!(diagnostics.key_report(0, 0).stuck);
+    }
+
+    #[test]
+    fn chattering_key_is_masked() {
+        let mut diagnostics = diagnostics(true);
+        for ticks in [0, 5, 100, 105, 200] {
+            poll(&mut diagnostics, ticks % 10 == 0, ticks);
+        }
+        assert!(diagnostics.key_report(0, 0).masked);
+        assert!(!diagnostics.just_pressed(0, 0));
+    }
+
+    #[test]
+    fn failed_read_line() {
+        let mut diagnostics = diagnostics(false);
+        // With ColToRow, read line 1 is column 1.
+        diagnostics.scanner().next = [[false, true], [true, true]];
+        diagnostics.poll(Instant::from_ticks(0)).unwrap();
+        diagnostics.poll(Instant::from_ticks(2000)).unwrap();
+
+        assert!(diagnostics.report().stuck_keys == 3);
+        assert!(!diagnostics.read_line_failed::<ColToRow>(0));
+        assert!(diagnostics.read_line_failed::<ColToRow>(1));
+    }
+}
@@ -1,13 +1,23 @@
 //! Typestate for declaring diode presence and direction.
 
+use core::marker::PhantomData;
+
 /// The logical/visual position of a key, in terms of the row and column.
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct KeyPosition {
     pub row: usize,
     pub col: usize,
 }
 
+impl KeyPosition {
+    pub const fn new(row: usize, col: usize) -> Self {
+        Self { row, col }
+    }
+}
+
 /// The position of a key on the switch matrix, in terms of the pins that would
 /// be read and written.
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct ScanPosition {
     pub write_index: usize,
     pub read_index: usize,
@@ -26,20 +36,24 @@ pub struct ScanPosition {
 /// being checked. While this is the logic used by the Polybius scanner, perhaps
 /// it may not always be true?
 ///
-/// # TODO: Generalizations
+/// # Generalizations
 ///
-/// The logic of this trait could be generalized to many other logical <->
+/// The logic of this trait can be generalized to many other logical <->
 /// electrical position mappings. For example, in some keyboards where the
 /// number of rows is much less than the number of columns, they optimize the
 /// number of matrix pins by combining adjacent columns, effectively halving the
 /// number of column pins and doubling row pins in the electrical switch matrix.
 ///
 /// As far as I know, in QMK, they only remap these at the keymap level by
-/// reordering the keycodes using macros. However, Rust's const-eval
-/// capabilities in theory allow us to perform this mapping at the
-/// matrix/scanner level by implementing a custom "DiodeConfiguration" that also
-/// incorporates such a mapping.
-pub trait DiodeConfiguration<const ROWS: usize, const COLS: usize> {
+/// reordering the keycodes using macros. Here, such a mapping is performed at
+/// the matrix/scanner level instead, by the [`Folded`] adaptor, so that the
+/// keymap can stay in visual order. Matrices that don't follow any regular
+/// pattern can be described by a [`Lookup`] table.
+///
+/// This is a const trait, so that adaptors like [`Folded`] can check the
+/// mapping of the configuration that they wrap at compile time; such
+/// configurations must be implemented with `impl const`.
+pub const trait DiodeConfiguration<const ROWS: usize, const COLS: usize> {
     /// Whether this matrix is susceptible to ghosting.
     const CAN_GHOST: bool;
 
@@ -54,10 +68,11 @@ pub trait DiodeConfiguration<const ROWS: usize, const COLS: usize> {
     /// This should be the inverse of [`key_position`].
     fn scan_position(pos: KeyPosition) -> ScanPosition;
 
-    /// Maps a matrix position to its corresponding logical position.
+    /// Maps a matrix position to its corresponding logical position, or `None`
+    /// if there is no key at that position.
     ///
     /// This should be the inverse [`scan_position`].
-    fn key_position(pos: ScanPosition) -> KeyPosition;
+    fn key_position(pos: ScanPosition) -> Option<KeyPosition>;
 }
 
 /// Diodes are present; current is allowed to flow from rows to columns.
@@ -68,7 +83,7 @@ pub struct RowToCol {
     _private: (),
 }
 
-impl<const ROWS: usize, const COLS: usize> DiodeConfiguration<ROWS, COLS> for RowToCol {
+impl<const ROWS: usize, const COLS: usize> const DiodeConfiguration<ROWS, COLS> for RowToCol {
     const CAN_GHOST: bool = false;
     const WRITE_LINES: usize = COLS;
     const READ_LINES: usize = ROWS;
@@ -82,11 +97,11 @@ impl<const ROWS: usize, const COLS: usize> DiodeConfiguration<ROWS, COLS> for Ro
     }
 
     #[inline]
-    fn key_position(pos: ScanPosition) -> KeyPosition {
-        KeyPosition {
+    fn key_position(pos: ScanPosition) -> Option<KeyPosition> {
+        Some(KeyPosition {
             row: pos.read_index,
             col: pos.write_index,
-        }
+        })
     }
 }
 
@@ -98,7 +113,7 @@ pub struct ColToRow {
     _private: (),
 }
 
-impl<const ROWS: usize, const COLS: usize> DiodeConfiguration<ROWS, COLS> for ColToRow {
+impl<const ROWS: usize, const COLS: usize> const DiodeConfiguration<ROWS, COLS> for ColToRow {
     const CAN_GHOST: bool = false;
     const WRITE_LINES: usize = ROWS;
     const READ_LINES: usize = COLS;
@@ -112,11 +127,11 @@ impl<const ROWS: usize, const COLS: usize> DiodeConfiguration<ROWS, COLS> for Co
     }
 
     #[inline]
-    fn key_position(pos: ScanPosition) -> KeyPosition {
-        KeyPosition {
+    fn key_position(pos: ScanPosition) -> Option<KeyPosition> {
+        Some(KeyPosition {
             row: pos.write_index,
             col: pos.read_index,
-        }
+        })
     }
 }
 
@@ -131,7 +146,7 @@ pub struct NoDiodes {
     _private: (),
 }
 
-impl<const ROWS: usize, const COLS: usize> DiodeConfiguration<ROWS, COLS> for NoDiodes {
+impl<const ROWS: usize, const COLS: usize> const DiodeConfiguration<ROWS, COLS> for NoDiodes {
     const CAN_GHOST: bool = true;
     const WRITE_LINES: usize = ROWS;
     const READ_LINES: usize = COLS;
@@ -145,10 +160,536 @@ impl<const ROWS: usize, const COLS: usize> DiodeConfiguration<ROWS, COLS> for No
     }
 
     #[inline]
-    fn key_position(pos: ScanPosition) -> KeyPosition {
-        KeyPosition {
+    fn key_position(pos: ScanPosition) -> Option<KeyPosition> {
+        Some(KeyPosition {
             row: pos.write_index,
             col: pos.read_index,
+        })
+    }
+}
+
+/// No matrix; every key is wired to its own pin.
+///
+/// This configuration has a single write line, and one read line per key. The
+/// read lines are assigned to the keys in row-major order, so read line `i`
+/// corresponds to row `i / COLS` and column `i % COLS`. It is meant to be used
+/// with [`DirectPins`](crate::scanner::DirectPins); other orderings can be
+/// described by a single-line [`Lookup`] table.
+pub struct RowMajor {
+    _private: (),
+}
+
+impl<const ROWS: usize, const COLS: usize> const DiodeConfiguration<ROWS, COLS> for RowMajor {
+    const CAN_GHOST: bool = false;
+    const WRITE_LINES: usize = 1;
+    const READ_LINES: usize = ROWS * COLS;
+
+    #[inline]
+    fn scan_position(pos: KeyPosition) -> ScanPosition {
+        ScanPosition {
+            write_index: 0,
+            read_index: pos.row * COLS + pos.col,
+        }
+    }
+
+    #[inline]
+    fn key_position(pos: ScanPosition) -> Option<KeyPosition> {
+        Some(KeyPosition {
+            row: pos.read_index / COLS,
+            col: pos.read_index % COLS,
+        })
+    }
+}
+
+/// Duplex matrix; diodes are present, and alternate direction between
+/// adjacent columns.
+///
+/// Each pair of adjacent columns shares a single column pin, so a matrix of
+/// `ROWS` row pins and `COLS / 2` column pins can scan `ROWS * COLS` keys. In
+/// even columns, current is allowed to flow from the column pin to the row pin
+/// (like [`ColToRow`]); in odd columns, it flows from the row pin to the column
+/// pin (like [`RowToCol`]).
+///
+/// Because every pin has to act as a write line in one phase of the scan and as
+/// a read line in the other, the write lines and the read lines are the same
+/// set of `ROWS + COLS / 2` lines: first the row pins, in order, followed by
+/// the column pins. Pairs of two row pins or two column pins do not correspond
+/// to any key. This configuration is meant to be used with a scanner that
+/// can switch the roles of its lines, like
+/// [`DuplexMatrix`](crate::scanner::DuplexMatrix).
+///
+/// `COLS` must be even.
+pub struct Duplex {
+    _private: (),
+}
+
+impl<const ROWS: usize, const COLS: usize> const DiodeConfiguration<ROWS, COLS> for Duplex {
+    const CAN_GHOST: bool = false;
+    const WRITE_LINES: usize = {
+        assert!(
+            COLS.is_multiple_of(2),
+            "duplex matrix must have an even number of columns"
+        );
+        ROWS + COLS / 2
+    };
+    const READ_LINES: usize = ROWS + COLS / 2;
+
+    #[inline]
+    fn scan_position(pos: KeyPosition) -> ScanPosition {
+        let col_line = ROWS + pos.col / 2;
+        if pos.col.is_multiple_of(2) {
+            ScanPosition {
+                write_index: pos.row,
+                read_index: col_line,
+            }
+        } else {
+            ScanPosition {
+                write_index: col_line,
+                read_index: pos.row,
+            }
+        }
+    }
+
+    #[inline]
+    fn key_position(pos: ScanPosition) -> Option<KeyPosition> {
+        let ScanPosition {
+            write_index,
+            read_index,
+        } = pos;
+        if write_index < ROWS && read_index >= ROWS {
+            Some(KeyPosition {
+                row: write_index,
+                col: (read_index - ROWS) * 2,
+            })
+        } else if write_index >= ROWS && read_index < ROWS {
+            Some(KeyPosition {
+                row: read_index,
+                col: (write_index - ROWS) * 2 + 1,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// Column-folding adaptor for another diode configuration.
+///
+/// Every `FACTOR` adjacent columns of the logical matrix are folded into a
+/// single column of the electrical matrix, each of them on its own row. The
+/// electrical matrix then has `ROWS * FACTOR` rows and `COLS / FACTOR` columns,
+/// which are scanned according to the `Inner` configuration.
+///
+/// For example, with a factor of 2, logical row `r` is split into electrical
+/// rows `2r` (even columns) and `2r + 1` (odd columns), and logical columns
+/// `2c` and `2c + 1` share electrical column `c`.
+///
+/// The folding is computed by the const functions [`fold`](Self::fold) and
+/// [`unfold`](Self::unfold). Evaluating this configuration's line counts
+/// asserts at compile time that `COLS` is a multiple of `FACTOR`, that every
+/// key maps to a scan position within the matrix and back to itself, and that
+/// every scan position with a key maps back to that scan position. This also
+/// checks the mapping of the `Inner` configuration, which must be implemented
+/// with `impl const`.
+///
+/// # Example
+///
+/// A 4x12 layout, folded into an 8x6 electrical matrix:
+///
+/// ```
+/// use polybius::diodes::{ColToRow, DiodeConfiguration, Folded};
+///
+/// pub type Diodes = Folded<ColToRow, 2>;
+///
+/// const _: () = assert!(<Diodes as DiodeConfiguration<4, 12>>::WRITE_LINES == 8);
+/// ```
+///
+/// The number of columns must be a multiple of the factor:
+///
+/// ```compile_fail
+/// use polybius::diodes::{ColToRow, DiodeConfiguration, Folded};
+///
+/// pub type Diodes = Folded<ColToRow, 5>;
+///
+/// const _: () = assert!(<Diodes as DiodeConfiguration<4, 12>>::WRITE_LINES == 20);
+/// ```
+///
+/// Neither does an inner configuration whose mappings aren't inverses of each
+/// other:
+///
+/// ```compile_fail
+/// #![feature(const_trait_impl)]
+///
+/// use polybius::diodes::{DiodeConfiguration, Folded, KeyPosition, ScanPosition};
+///
+/// pub struct Broken;
+///
+/// impl<const ROWS: usize, const COLS: usize> const DiodeConfiguration<ROWS, COLS> for Broken {
+///     const CAN_GHOST: bool = false;
+///     const WRITE_LINES: usize = ROWS;
+///     const READ_LINES: usize = COLS;
+///
+///     fn scan_position(pos: KeyPosition) -> ScanPosition {
+///         ScanPosition {
+///             write_index: pos.row,
+///             read_index: pos.col,
+///         }
+///     }
+///
+///     // Reads the lines the wrong way around.
+///     fn key_position(pos: ScanPosition) -> Option<KeyPosition> {
+///         Some(KeyPosition::new(pos.read_index, pos.write_index))
+///     }
+/// }
+///
+/// pub type Diodes = Folded<Broken, 2>;
+///
+/// const _: usize = <Diodes as DiodeConfiguration<2, 4>>::WRITE_LINES;
+/// ```
+pub struct Folded<Inner, const FACTOR: usize> {
+    _inner: PhantomData<Inner>,
+}
+
+impl<Inner, const FACTOR: usize> Folded<Inner, FACTOR> {
+    /// Maps a logical position to its position in the folded matrix.
+    pub const fn fold(pos: KeyPosition) -> KeyPosition {
+        KeyPosition {
+            row: pos.row * FACTOR + pos.col % FACTOR,
+            col: pos.col / FACTOR,
         }
     }
+
+    /// Maps a position in the folded matrix to its logical position.
+    pub const fn unfold(pos: KeyPosition) -> KeyPosition {
+        KeyPosition {
+            row: pos.row / FACTOR,
+            col: pos.col * FACTOR + pos.row % FACTOR,
+        }
+    }
+
+    /// Checks that the folding of a `ROWS` by `COLS` matrix is valid, and that
+    /// the folded configuration maps every key to its own scan position and
+    /// back.
+    const fn check<const ROWS: usize, const COLS: usize>()
+    where
+        Inner: const DiodeConfiguration<{ ROWS * FACTOR }, { COLS / FACTOR }>,
+    {
+        assert!(FACTOR > 0, "folding factor must be nonzero");
+        assert!(
+            COLS.is_multiple_of(FACTOR),
+            "number of columns must be a multiple of the folding factor"
+        );
+        let write_lines = Inner::WRITE_LINES;
+        let read_lines = Inner::READ_LINES;
+
+        let mut row = 0;
+        while row < ROWS {
+            let mut col = 0;
+            while col < COLS {
+                let folded = Self::fold(KeyPosition { row, col });
+                assert!(
+                    folded.row < ROWS * FACTOR && folded.col < COLS / FACTOR,
+                    "folded position out of bounds"
+                );
+                let scan = Inner::scan_position(folded);
+                assert!(
+                    scan.write_index < write_lines && scan.read_index < read_lines,
+                    "scan position out of bounds"
+                );
+                let key = match Inner::key_position(scan) {
+                    Some(key) => Self::unfold(key),
+                    None => panic!("scan position of a key has no key"),
+                };
+                assert!(
+                    key.row == row && key.col == col,
+                    "key position is not the inverse of scan position"
+                );
+                col += 1;
+            }
+            row += 1;
+        }
+
+        let mut write_index = 0;
+        while write_index < write_lines {
+            let mut read_index = 0;
+            while read_index < read_lines {
+                let scan = ScanPosition {
+                    write_index,
+                    read_index,
+                };
+                if let Some(key) = Inner::key_position(scan) {
+                    assert!(
+                        key.row < ROWS * FACTOR && key.col < COLS / FACTOR,
+                        "key position out of bounds"
+                    );
+                    let round_trip = Inner::scan_position(key);
+                    assert!(
+                        round_trip.write_index == write_index
+                            && round_trip.read_index == read_index,
+                        "scan position is not the inverse of key position"
+                    );
+                }
+                read_index += 1;
+            }
+            write_index += 1;
+        }
+    }
+}
+
+impl<Inner, const FACTOR: usize, const ROWS: usize, const COLS: usize> const
+    DiodeConfiguration<ROWS, COLS> for Folded<Inner, FACTOR>
+where
+    Inner: const DiodeConfiguration<{ ROWS * FACTOR }, { COLS / FACTOR }>,
+{
+    const CAN_GHOST: bool = Inner::CAN_GHOST;
+    const WRITE_LINES: usize = {
+        Self::check::<ROWS, COLS>();
+        Inner::WRITE_LINES
+    };
+    const READ_LINES: usize = {
+        Self::check::<ROWS, COLS>();
+        Inner::READ_LINES
+    };
+
+    #[inline]
+    fn scan_position(pos: KeyPosition) -> ScanPosition {
+        Inner::scan_position(Self::fold(pos))
+    }
+
+    #[inline]
+    fn key_position(pos: ScanPosition) -> Option<KeyPosition> {
+        match Inner::key_position(pos) {
+            Some(pos) => Some(Self::unfold(pos)),
+            None => None,
+        }
+    }
+}
+
+/// A matrix layout described by a lookup table, for use with [`Lookup`].
+pub trait LookupTable<const ROWS: usize, const COLS: usize> {
+    /// Whether this matrix is susceptible to ghosting.
+    const CAN_GHOST: bool;
+
+    /// The logical position of the key at each matrix position, indexed by
+    /// `[write_index][read_index]`, or `None` if there is no key at that
+    /// position.
+    ///
+    /// Every entry must have the same length. Every logical position in the
+    /// `ROWS` by `COLS` layout must appear exactly once.
+    const TABLE: &'static [&'static [Option<KeyPosition>]];
+}
+
+/// Arbitrary mapping between logical and matrix positions, defined by a lookup
+/// table.
+///
+/// This is useful for PCBs with irregular matrices, where some intersections
+/// of write and read lines are unused, and where the logical positions of the
+/// keys don't follow the order of the lines. The table is validated at compile
+/// time, when this configuration's line counts are evaluated.
+///
+/// # Example
+///
+/// ```
+/// use polybius::diodes::{KeyPosition, Lookup, LookupTable};
+///
+/// pub struct MyTable;
+///
+/// const fn key(row: usize, col: usize) -> Option<KeyPosition> {
+///     Some(KeyPosition::new(row, col))
+/// }
+///
+/// impl LookupTable<2, 3> for MyTable {
+///     const CAN_GHOST: bool = false;
+///     const TABLE: &'static [&'static [Option<KeyPosition>]] = &[
+///         &[key(0, 0), key(1, 2), None],
+///         &[key(0, 2), key(1, 0), key(0, 1)],
+///         &[None, None, key(1, 1)],
+///     ];
+/// }
+///
+/// pub type Diodes = Lookup<MyTable>;
+/// ```
+///
+/// A table that maps a key twice fails to compile:
+///
+/// ```compile_fail
+/// use polybius::diodes::{DiodeConfiguration, KeyPosition, Lookup, LookupTable};
+///
+/// pub struct MyTable;
+///
+/// const fn key(row: usize, col: usize) -> Option<KeyPosition> {
+///     Some(KeyPosition::new(row, col))
+/// }
+///
+/// impl LookupTable<1, 3> for MyTable {
+///     const CAN_GHOST: bool = false;
+///     const TABLE: &'static [&'static [Option<KeyPosition>]] = &[&[key(0, 0), key(0, 1), key(0, 1)]];
+/// }
+///
+/// const _: usize = <Lookup<MyTable> as DiodeConfiguration<1, 3>>::WRITE_LINES;
+/// ```
+///
+/// So does a table that leaves a key out:
+///
+/// ```compile_fail
+/// use polybius::diodes::{DiodeConfiguration, KeyPosition, Lookup, LookupTable};
+///
+/// pub struct MyTable;
+///
+/// const fn key(row: usize, col: usize) -> Option<KeyPosition> {
+///     Some(KeyPosition::new(row, col))
+/// }
+///
+/// impl LookupTable<1, 3> for MyTable {
+///     const CAN_GHOST: bool = false;
+///     const TABLE: &'static [&'static [Option<KeyPosition>]] = &[&[key(0, 0), None, key(0, 2)]];
+/// }
+///
+/// const _: usize = <Lookup<MyTable> as DiodeConfiguration<1, 3>>::WRITE_LINES;
+/// ```
+pub struct Lookup<T> {
+    _table: PhantomData<T>,
+}
+
+impl<T> Lookup<T> {
+    /// Checks that `table` maps every position in a `ROWS` by `COLS` layout
+    /// exactly once, and returns the inverse mapping.
+    const fn invert<const ROWS: usize, const COLS: usize>(
+        table: &[&[Option<KeyPosition>]],
+    ) -> [[ScanPosition; COLS]; ROWS] {
+        assert!(!table.is_empty(), "lookup table is empty");
+
+        let mut inverse = [[ScanPosition {
+            write_index: 0,
+            read_index: 0,
+        }; COLS]; ROWS];
+        let mut seen = [[false; COLS]; ROWS];
+
+        let mut write_index = 0;
+        while write_index < table.len() {
+            assert!(
+                table[write_index].len() == table[0].len(),
+                "lookup table entries have different lengths"
+            );
+            let mut read_index = 0;
+            while read_index < table[write_index].len() {
+                if let Some(KeyPosition { row, col }) = table[write_index][read_index] {
+                    assert!(
+                        row < ROWS && col < COLS,
+                        "lookup table key position out of bounds"
+                    );
+                    assert!(!seen[row][col], "lookup table has duplicate key positions");
+                    seen[row][col] = true;
+                    inverse[row][col] = ScanPosition {
+                        write_index,
+                        read_index,
+                    };
+                }
+                read_index += 1;
+            }
+            write_index += 1;
+        }
+
+        let mut row = 0;
+        while row < ROWS {
+            let mut col = 0;
+            while col < COLS {
+                assert!(seen[row][col], "lookup table is missing a key position");
+                col += 1;
+            }
+            row += 1;
+        }
+
+        inverse
+    }
+}
+
+/// The inverse of a [`LookupTable`], computed at compile time.
+struct LookupInverse<T, const ROWS: usize, const COLS: usize> {
+    _table: PhantomData<T>,
+}
+
+impl<T, const ROWS: usize, const COLS: usize> LookupInverse<T, ROWS, COLS>
+where
+    T: LookupTable<ROWS, COLS>,
+{
+    const TABLE: [[ScanPosition; COLS]; ROWS] = Lookup::<T>::invert(T::TABLE);
+}
+
+impl<T, const ROWS: usize, const COLS: usize> const DiodeConfiguration<ROWS, COLS> for Lookup<T>
+where
+    T: LookupTable<ROWS, COLS>,
+{
+    const CAN_GHOST: bool = T::CAN_GHOST;
+    const WRITE_LINES: usize = {
+        let _ = LookupInverse::<T, ROWS, COLS>::TABLE;
+        T::TABLE.len()
+    };
+    const READ_LINES: usize = {
+        let _ = LookupInverse::<T, ROWS, COLS>::TABLE;
+        T::TABLE[0].len()
+    };
+
+    #[inline]
+    fn scan_position(pos: KeyPosition) -> ScanPosition {
+        LookupInverse::<T, ROWS, COLS>::TABLE[pos.row][pos.col]
+    }
+
+    #[inline]
+    fn key_position(pos: ScanPosition) -> Option<KeyPosition> {
+        T::TABLE[pos.write_index][pos.read_index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Asserts that every key has its own scan position within the matrix, and
+    /// that `key_position` maps it back to the key.
+    fn assert_round_trip<D, const ROWS: usize, const COLS: usize>()
+    where
+        D: DiodeConfiguration<ROWS, COLS>,
+    {
+        let mut seen = [[false; 64]; 64];
+        for row in 0..ROWS {
+            for col in 0..COLS {
+                let pos = KeyPosition::new(row, col);
+                let scan = D::scan_position(pos);
+                assert!(scan.write_index < D::WRITE_LINES && scan.read_index < D::READ_LINES);
+                assert!(!seen[scan.write_index][scan.read_index]);
+                seen[scan.write_index][scan.read_index] = true;
+                assert!(D::key_position(scan) == Some(pos));
+            }
+        }
+        for (write_index, seen) in seen.iter().enumerate().take(D::WRITE_LINES) {
+            for (read_index, &seen) in seen.iter().enumerate().take(D::READ_LINES) {
+                let scan = ScanPosition {
+                    write_index,
+                    read_index,
+                };
+                assert!(D::key_position(scan).is_some() == seen);
+            }
+        }
+    }
+
+    struct Reversed;
+
+    impl LookupTable<2, 2> for Reversed {
+        const CAN_GHOST: bool = false;
+        const TABLE: &'static [&'static [Option<KeyPosition>]] = &[
+            &[Some(KeyPosition::new(1, 1)), Some(KeyPosition::new(1, 0))],
+            &[Some(KeyPosition::new(0, 1)), Some(KeyPosition::new(0, 0))],
+        ];
+    }
+
+    #[test]
+    fn folded_round_trip() {
+        assert_round_trip::<Folded<ColToRow, 2>, 4, 12>();
+        assert_round_trip::<Folded<RowToCol, 3>, 4, 12>();
+        assert_round_trip::<Folded<NoDiodes, 1>, 4, 12>();
+        assert_round_trip::<Folded<RowMajor, 4>, 2, 8>();
+        assert_round_trip::<Folded<Duplex, 2>, 2, 8>();
+        assert_round_trip::<Folded<Folded<ColToRow, 2>, 3>, 2, 12>();
+        assert_round_trip::<Folded<Lookup<Reversed>, 2>, 1, 4>();
+    }
 }
@@ -0,0 +1,402 @@
+//! Electrostatic capacitive (EC) key scanning.
+//!
+//! In an EC keyboard, like those with Topre switches, each key is a variable
+//! capacitor between a write line and a read line, whose capacitance rises as
+//! the key is pressed. A key is sensed by discharging the sense circuit on its
+//! read line, charging its write line, and sampling how much charge was
+//! coupled onto the read line. The [`EcScanner`] compares each sample against
+//! thresholds relative to the key's calibrated rest level.
+
+use crate::analog::{select_channel, ChannelCount};
+use crate::debounce::NoDebounce;
+use crate::diodes::{DiodeConfiguration, KeyPosition, ScanPosition};
+use crate::pin_group::OutputGroup;
+use crate::scanner::{row_bytes, KeyEvent, MatrixState, ScanRow, Scanner};
+use crate::time::Instant;
+use core::cmp::max;
+use core::marker::PhantomData;
+use embedded_hal::adc::{Channel, OneShot};
+use embedded_hal::digital::v2::OutputPin;
+
+/// The sense circuit of an EC matrix, with `WRITE_LINES` write lines and
+/// `READ_LINES` read lines.
+///
+/// Sensing a key is a sequence of two steps: [`discharge()`](Self::discharge)
+/// followed by [`sample()`](Self::sample), for the same position.
+pub trait EcSense<const WRITE_LINES: usize, const READ_LINES: usize> {
+    type Error;
+
+    /// Selects the read line of the key at `pos`, and discharges the sense
+    /// circuit.
+    fn discharge(&mut self, pos: ScanPosition) -> Result<(), Self::Error>;
+
+    /// Charges the write line of the key at `pos`, samples the level coupled
+    /// onto its read line, and releases the write line.
+    fn sample(&mut self, pos: ScanPosition) -> Result<u16, Self::Error>;
+}
+
+/// An EC sense circuit made of GPIO pins and an ADC.
+///
+/// - The write lines are the pins in `strobe`, which are driven high to charge
+///   a line.
+/// - The read lines are the channels of an analog multiplexer, selected by the
+///   `BITS` pins in `select`, in binary, least significant bit first.
+/// - The `discharge` pin drains the sense circuit while it is low.
+/// - The output of the sense circuit is sampled by the ADC on `pin`.
+///
+/// `charge_delay` is called between charging a write line and sampling the
+/// read line, to give the sense circuit time to settle.
+pub struct EcPins<W, S, X, A, M, P, D, const BITS: usize> {
+    strobe: W,
+    select: S,
+    discharge: X,
+    adc: A,
+    pin: P,
+    charge_delay: D,
+    _adc: PhantomData<M>,
+}
+
+impl<W, S, X, A, M, P, D, const BITS: usize> EcPins<W, S, X, A, M, P, D, BITS>
+where
+    S: OutputGroup<BITS>,
+    X: OutputPin<Error = S::Error>,
+    A: OneShot<M, u16, P>,
+    P: Channel<M>,
+    D: FnMut(),
+{
+    pub fn new(strobe: W, select: S, discharge: X, adc: A, pin: P, charge_delay: D) -> Self {
+        Self {
+            strobe,
+            select,
+            discharge,
+            adc,
+            pin,
+            charge_delay,
+            _adc: PhantomData,
+        }
+    }
+}
+
+/// An error from one of the parts of an [`EcPins`].
+#[derive(Debug)]
+pub enum EcPinsError<A, P> {
+    /// The ADC failed to sample the sense circuit.
+    Adc(A),
+    /// One of the GPIO pins could not be set.
+    Pin(P),
+}
+
+impl<W, S, X, A, M, P, D, const BITS: usize, const WRITE_LINES: usize, const READ_LINES: usize>
+    EcSense<WRITE_LINES, READ_LINES> for EcPins<W, S, X, A, M, P, D, BITS>
+where
+    W: OutputGroup<WRITE_LINES>,
+    S: OutputGroup<BITS, Error = W::Error>,
+    X: OutputPin<Error = W::Error>,
+    A: OneShot<M, u16, P>,
+    P: Channel<M>,
+    D: FnMut(),
+{
+    type Error = EcPinsError<A::Error, W::Error>;
+
+    fn discharge(&mut self, pos: ScanPosition) -> Result<(), Self::Error> {
+        let () = ChannelCount::<READ_LINES, BITS>::CHECK;
+        select_channel(&mut self.select, pos.read_index).map_err(EcPinsError::Pin)?;
+        self.discharge.set_low().map_err(EcPinsError::Pin)
+    }
+
+    fn sample(&mut self, pos: ScanPosition) -> Result<u16, Self::Error> {
+        self.discharge.set_high().map_err(EcPinsError::Pin)?;
+        self.strobe
+            .set_high(pos.write_index)
+            .map_err(EcPinsError::Pin)?;
+        (self.charge_delay)();
+        let level = nb::block!(self.adc.read(&mut self.pin)).map_err(EcPinsError::Adc);
+        self.strobe
+            .set_low(pos.write_index)
+            .map_err(EcPinsError::Pin)?;
+        level
+    }
+}
+
+/// The levels at which an EC key is pressed and released, relative to the
+/// key's rest level.
+#[derive(Clone, Copy)]
+pub struct Thresholds {
+    /// The key is pressed when its level rises this far above its rest level.
+    pub press: u16,
+    /// The key is released when its level falls back below this far above its
+    /// rest level.
+    ///
+    /// This should be lower than `press`, to keep noise around the threshold
+    /// from toggling the key.
+    pub release: u16,
+}
+
+/// A scanner for EC matrices.
+///
+/// The layout of the matrix is given by the configuration `C`, as for a
+/// [`ScanMatrix`](crate::scanner::ScanMatrix). EC matrices cannot ghost, since
+/// each key is sensed on its own.
+///
+/// Every key's rest level starts at zero. Call
+/// [`calibrate()`](Self::calibrate) while no keys are pressed to measure the
+/// actual rest levels, which vary from key to key.
+pub struct EcScanner<L, C, const ROWS: usize, const COLS: usize>
+where
+    [(); row_bytes(COLS)]:,
+{
+    lines: L,
+    thresholds: Thresholds,
+    rest: [[u16; COLS]; ROWS],
+    state: MatrixState<C, NoDebounce, ROWS, COLS>,
+}
+
+impl<L, C, const ROWS: usize, const COLS: usize> EcScanner<L, C, ROWS, COLS>
+where
+    C: DiodeConfiguration<ROWS, COLS>,
+    L: EcSense<{ C::WRITE_LINES }, { C::READ_LINES }>,
+    [(); row_bytes(COLS)]:,
+{
+    pub fn new(lines: L, thresholds: Thresholds) -> Self {
+        Self {
+            lines,
+            thresholds,
+            rest: [[0; COLS]; ROWS],
+            state: MatrixState::new(NoDebounce),
+        }
+    }
+
+    /// Senses every key `samples` times, and uses the highest level seen for
+    /// each key as its rest level.
+    ///
+    /// This should be called while none of the keys are pressed, for example
+    /// at startup.
+    pub fn calibrate(&mut self, samples: usize) -> Result<(), L::Error> {
+        self.rest = [[0; COLS]; ROWS];
+        for _ in 0..samples {
+            for row in 0..ROWS {
+                for col in 0..COLS {
+                    let pos = C::scan_position(KeyPosition { row, col });
+                    let level = self.sense(pos)?;
+                    self.rest[row][col] = max(self.rest[row][col], level);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn rest(&self, row: usize, col: usize) -> u16 {
+        self.rest[row][col]
+    }
+
+    pub fn thresholds(&self) -> Thresholds {
+        self.thresholds
+    }
+
+    pub fn set_thresholds(&mut self, thresholds: Thresholds) {
+        self.thresholds = thresholds;
+    }
+
+    fn sense(&mut self, pos: ScanPosition) -> Result<u16, L::Error> {
+        self.lines.discharge(pos)?;
+        self.lines.sample(pos)
+    }
+}
+
+impl<L, C, const ROWS: usize, const COLS: usize> Scanner<ROWS, COLS> for EcScanner<L, C, ROWS, COLS>
+where
+    C: DiodeConfiguration<ROWS, COLS>,
+    L: EcSense<{ C::WRITE_LINES }, { C::READ_LINES }>,
+    [(); row_bytes(COLS)]:,
+    [(); row_bytes(C::READ_LINES)]:,
+    [(); C::WRITE_LINES]:,
+{
+    type Error = L::Error;
+
+    fn poll(&mut self, now: Instant) -> Result<(), Self::Error> {
+        let mut scan_lines = [ScanRow::<{ C::READ_LINES }>::EMPTY; C::WRITE_LINES];
+        for (i, scan_line) in scan_lines.iter_mut().enumerate() {
+            for j in 0..C::READ_LINES {
+                let pos = ScanPosition {
+                    write_index: i,
+                    read_index: j,
+                };
+                let Some(KeyPosition { row, col }) = C::key_position(pos) else {
+                    continue;
+                };
+                let level = self.sense(pos)?.saturating_sub(self.rest[row][col]);
+                let threshold = if self.state.is_pressed(row, col) {
+                    self.thresholds.release
+                } else {
+                    self.thresholds.press
+                };
+                scan_line.set(j, level >= threshold);
+            }
+        }
+        self.state.update(now, &scan_lines);
+        Ok(())
+    }
+
+    fn is_pressed(&self, row: usize, col: usize) -> bool {
+        self.state.is_pressed(row, col)
+    }
+
+    fn just_pressed(&self, row: usize, col: usize) -> bool {
+        self.state.just_pressed(row, col)
+    }
+
+    fn just_released(&self, row: usize, col: usize) -> bool {
+        self.state.just_released(row, col)
+    }
+
+    fn for_each_event<F>(&self, timestamp: Instant, f: F)
+    where
+        F: FnMut(KeyEvent),
+    {
+        self.state.for_each_event(timestamp, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::diodes::RowToCol;
+
+    use core::convert::Infallible;
+    use embedded_hal_mock::adc::{self, MockChan0};
+    use embedded_hal_mock::pin::{self, State};
+    use std::string::String;
+    use std::vec;
+    use std::vec::Vec;
+
+    /// A simulated sense circuit for a 1x2 matrix, replaying the given
+    /// sequence of levels for each key.
+    struct FakeSense {
+        levels: [vec::IntoIter<u16>; 2],
+        discharged: Option<ScanPosition>,
+        log: Vec<usize>,
+    }
+
+    impl FakeSense {
+        fn new(levels: [Vec<u16>; 2]) -> Self {
+            Self {
+                levels: levels.map(Vec::into_iter),
+                discharged: None,
+                log: Vec::new(),
+            }
+        }
+    }
+
+    impl EcSense<2, 1> for FakeSense {
+        type Error = Infallible;
+
+        fn discharge(&mut self, pos: ScanPosition) -> Result<(), Self::Error> {
+            self.discharged = Some(pos);
+            Ok(())
+        }
+
+        fn sample(&mut self, pos: ScanPosition) -> Result<u16, Self::Error> {
+            // Every sample must be preceded by discharging the same key.
+            assert!(self.discharged.take() == Some(pos));
+            self.log.push(pos.write_index);
+            Ok(self.levels[pos.write_index].next().unwrap())
+        }
+    }
+
+    const THRESHOLDS: Thresholds = Thresholds {
+        press: 100,
+        release: 60,
+    };
+
+    /// Polls the scanner once per level, returning the edges reported for each
+    /// key (`P` just pressed, `R` just released, `-` no change).
+    fn edges<S: Scanner<1, 2>>(scanner: &mut S, scans: usize) -> [String; 2] {
+        let mut edges = [String::new(), String::new()];
+        for _ in 0..scans {
+            scanner.poll(Instant::from_ticks(0)).ok().unwrap();
+            for (col, edges) in edges.iter_mut().enumerate() {
+                edges.push(if scanner.just_pressed(0, col) {
+                    'P'
+                } else if scanner.just_released(0, col) {
+                    'R'
+                } else {
+                    '-'
+                });
+            }
+        }
+        edges
+    }
+
+    #[test]
+    fn hysteresis() {
+        let sense = FakeSense::new([
+            vec![0, 50, 99, 120, 80, 110, 61, 59, 90, 0],
+            vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        ]);
+        let mut scanner: EcScanner<_, RowToCol, 1, 2> = EcScanner::new(sense, THRESHOLDS);
+
+        let [a, b] = edges(&mut scanner, 10);
+        assert_eq!(a, "---P---R--");
+        assert_eq!(b, "----------");
+        assert_eq!(scanner.lines.log, [0, 1].repeat(10));
+    }
+
+    #[test]
+    fn calibrate() {
+        let sense = FakeSense::new([
+            vec![300, 320, 310, 400, 430, 390],
+            vec![50, 40, 45, 100, 200, 90],
+        ]);
+        let mut scanner: EcScanner<_, RowToCol, 1, 2> = EcScanner::new(sense, THRESHOLDS);
+        scanner.calibrate(3).unwrap();
+        assert_eq!(scanner.rest(0, 0), 320);
+        assert_eq!(scanner.rest(0, 1), 50);
+
+        let [a, b] = edges(&mut scanner, 3);
+        assert_eq!(a, "-P-");
+        assert_eq!(b, "-PR");
+    }
+
+    #[test]
+    fn ec_pins_sequence() {
+        let mut strobe = [
+            pin::Mock::new(&[]),
+            pin::Mock::new(&[
+                pin::Transaction::set(State::High),
+                pin::Transaction::set(State::Low),
+            ]),
+        ];
+        let mut select = [
+            pin::Mock::new(&[pin::Transaction::set(State::High)]),
+            pin::Mock::new(&[pin::Transaction::set(State::Low)]),
+        ];
+        let mut discharge = pin::Mock::new(&[
+            pin::Transaction::set(State::Low),
+            pin::Transaction::set(State::High),
+        ]);
+        let mut adc = adc::Mock::new(&[adc::Transaction::read(0, 345)]);
+
+        let mut sense = EcPins::new(
+            strobe.clone(),
+            select.clone(),
+            discharge.clone(),
+            adc.clone(),
+            MockChan0,
+            || {},
+        );
+        let pos = ScanPosition {
+            write_index: 1,
+            read_index: 1,
+        };
+        EcSense::<2, 4>::discharge(&mut sense, pos).unwrap();
+        assert_eq!(EcSense::<2, 4>::sample(&mut sense, pos).ok(), Some(345));
+
+        for pin in strobe.iter_mut().chain(&mut select) {
+            pin.done();
+        }
+        discharge.done();
+        adc.done();
+    }
+}
@@ -0,0 +1,236 @@
+//! Rotary encoders.
+
+use core::convert::Infallible;
+use embedded_hal::digital::v2::InputPin;
+
+/// The direction of a single step of a rotary encoder.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Clockwise,
+    CounterClockwise,
+}
+
+/// Rotary encoder interface for keyboard hardware.
+///
+/// A single component may provide any number of encoders, which are
+/// identified by their index. For keyboards that do not have any encoders, the
+/// type [`NoEncoder`] provides a no-op implementation.
+pub trait Encoder {
+    type Error;
+
+    /// The number of encoders provided by this component.
+    const COUNT: usize;
+
+    /// Samples the encoders, and records any steps they were turned by.
+    ///
+    /// This should be called often enough to catch every transition of the
+    /// encoders' outputs.
+    fn poll(&mut self) -> Result<(), Self::Error>;
+
+    /// Takes one of the recorded steps of the encoder at `index`, if there are
+    /// any.
+    fn take_step(&mut self, index: usize) -> Option<Direction>;
+}
+
+/// A no-op encoder implementation that can be used by keyboards that do not
+/// have any encoders.
+pub struct NoEncoder;
+
+impl Encoder for NoEncoder {
+    type Error = Infallible;
+
+    const COUNT: usize = 0;
+
+    fn poll(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn take_step(&mut self, index: usize) -> Option<Direction> {
+        let _ = index;
+        None
+    }
+}
+
+/// Several encoder components, whose encoders are numbered in order.
+impl<T, const N: usize> Encoder for [T; N]
+where
+    T: Encoder,
+{
+    type Error = T::Error;
+
+    const COUNT: usize = N * T::COUNT;
+
+    fn poll(&mut self) -> Result<(), Self::Error> {
+        for encoder in self {
+            encoder.poll()?;
+        }
+        Ok(())
+    }
+
+    fn take_step(&mut self, index: usize) -> Option<Direction> {
+        self[index / T::COUNT].take_step(index % T::COUNT)
+    }
+}
+
+/// Change in position for each transition between quadrature states, indexed
+/// by `old_state << 2 | new_state`, where each state is `a << 1 | b`.
+///
+/// Going clockwise, the states follow the Gray code sequence 00, 01, 11, 10.
+/// Transitions where both outputs changed at once are ambiguous, and ignored.
+const TRANSITIONS: [i8; 16] = [0, 1, -1, 0, -1, 0, 0, 1, 1, 0, 0, -1, 0, -1, 1, 0];
+
+/// A single quadrature-encoded rotary encoder, with its two outputs connected
+/// to the input pins `a` and `b`.
+///
+/// `resolution` is the number of quadrature transitions per step; most
+/// encoders with detents go through 4 transitions (one full cycle) between
+/// each detent. If the encoder steps in the wrong direction, swap `a` and `b`.
+pub struct Quadrature<A, B> {
+    a: A,
+    b: B,
+    resolution: i8,
+    state: Option<u8>,
+    position: i8,
+    steps: i8,
+}
+
+impl<A, B> Quadrature<A, B>
+where
+    A: InputPin,
+    B: InputPin<Error = A::Error>,
+{
+    /// # Panics
+    ///
+    /// Panics if `resolution` is zero or greater than 127.
+    pub fn new(a: A, b: B, resolution: u8) -> Self {
+        assert!(
+            resolution > 0 && resolution <= i8::MAX as u8,
+            "invalid encoder resolution"
+        );
+        Self {
+            a,
+            b,
+            resolution: resolution as i8,
+            state: None,
+            position: 0,
+            steps: 0,
+        }
+    }
+}
+
+impl<A, B> Encoder for Quadrature<A, B>
+where
+    A: InputPin,
+    B: InputPin<Error = A::Error>,
+{
+    type Error = A::Error;
+
+    const COUNT: usize = 1;
+
+    fn poll(&mut self) -> Result<(), Self::Error> {
+        let state = (u8::from(self.a.is_high()?) << 1) | u8::from(self.b.is_high()?);
+        if let Some(old_state) = self.state {
+            self.position += TRANSITIONS[usize::from(old_state << 2 | state)];
+            if self.position >= self.resolution {
+                self.position = 0;
+                self.steps = self.steps.saturating_add(1);
+            } else if self.position <= -self.resolution {
+                self.position = 0;
+                self.steps = self.steps.saturating_sub(1);
+            }
+        }
+        self.state = Some(state);
+        Ok(())
+    }
+
+    fn take_step(&mut self, index: usize) -> Option<Direction> {
+        let _ = index;
+        if self.steps > 0 {
+            self.steps -= 1;
+            Some(Direction::Clockwise)
+        } else if self.steps < 0 {
+            self.steps += 1;
+            Some(Direction::CounterClockwise)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    use embedded_hal_mock::pin::{Mock, State, Transaction};
+    use std::vec::Vec;
+
+    /// Polls an encoder through the given sequence of `a`/`b` states, taking
+    /// all of the recorded steps after each poll.
+    fn run(resolution: u8, states: &[(u8, u8)]) -> Vec<Option<Direction>> {
+        let pin = |output: fn(&(u8, u8)) -> u8| {
+            Mock::new(
+                &states
+                    .iter()
+                    .map(|s| {
+                        Transaction::get(if output(s) == 1 {
+                            State::High
+                        } else {
+                            State::Low
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        };
+        let mut a = pin(|s| s.0);
+        let mut b = pin(|s| s.1);
+
+        let mut encoder = Quadrature::new(a.clone(), b.clone(), resolution);
+        let mut steps = Vec::new();
+        for _ in states {
+            encoder.poll().unwrap();
+            steps.push(encoder.take_step(0));
+        }
+        assert!(encoder.take_step(0).is_none());
+
+        a.done();
+        b.done();
+        steps
+    }
+
+    const CW: Option<Direction> = Some(Direction::Clockwise);
+    const CCW: Option<Direction> = Some(Direction::CounterClockwise);
+
+    #[test]
+    fn full_cycle_per_step() {
+        let steps = run(
+            4,
+            &[
+                (0, 0),
+                (0, 1),
+                (1, 1),
+                (1, 0),
+                (0, 0),
+                (1, 0),
+                (1, 1),
+                (0, 1),
+                (0, 0),
+            ],
+        );
+        assert!(steps == [None, None, None, None, CW, None, None, None, CCW]);
+    }
+
+    #[test]
+    fn bounce_does_not_step() {
+        let steps = run(4, &[(0, 0), (0, 1), (0, 0), (0, 1), (1, 1), (0, 1), (0, 0)]);
+        assert!(steps.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn single_transition_per_step() {
+        let steps = run(1, &[(0, 0), (0, 1), (1, 1), (0, 1), (1, 1), (0, 0)]);
+        // The last transition changes both outputs, and is ignored.
+        assert!(steps == [None, CW, CW, CCW, CW, None]);
+    }
+}
@@ -0,0 +1,294 @@
+//! Matrix lines behind I2C GPIO expanders.
+//!
+//! Large and split boards often put some of their matrix lines on a 16-pin
+//! port expander, like the MCP23017 or the PCA9555. Each scan line only costs
+//! a single bus transaction: one write to select a write line, or one read to
+//! sample every read line at once.
+//!
+//! Both [`ExpanderOut`] and [`ExpanderIn`] take ownership of an I2C bus. To
+//! put write lines and read lines on the same expander, or several expanders
+//! on the same bus, give each one a proxy to a shared bus.
+
+use crate::scanner::{row_bytes, ReadLines, ScanRow, WriteLines};
+use core::marker::PhantomData;
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+
+/// The register map of a 16-pin port expander.
+///
+/// Each register is 16 bits wide, as a pair of 8-bit registers at consecutive
+/// addresses, with port 0 (pins 0-7) at the lower address and port 1 (pins
+/// 8-15) at the higher address. The chip must increment the register address
+/// automatically when reading or writing both of them in one transaction.
+pub trait Chip {
+    /// The register holding the level of each pin.
+    const INPUT: u8;
+
+    /// The register holding the level to drive on each output pin.
+    const OUTPUT: u8;
+
+    /// The register holding the direction of each pin, 1 for input and 0 for
+    /// output.
+    const DIRECTION: u8;
+
+    /// The register enabling the pull-up resistor of each pin, if the chip has
+    /// configurable pull-ups.
+    const PULL_UP: Option<u8>;
+}
+
+/// Microchip MCP23017, in its default register layout (`IOCON.BANK = 0`).
+pub struct Mcp23017 {
+    _private: (),
+}
+
+impl Chip for Mcp23017 {
+    const INPUT: u8 = 0x12;
+    const OUTPUT: u8 = 0x14;
+    const DIRECTION: u8 = 0x00;
+    const PULL_UP: Option<u8> = Some(0x0c);
+}
+
+/// NXP/TI PCA9555, whose pull-up resistors are always enabled.
+pub struct Pca9555 {
+    _private: (),
+}
+
+impl Chip for Pca9555 {
+    const INPUT: u8 = 0x00;
+    const OUTPUT: u8 = 0x02;
+    const DIRECTION: u8 = 0x06;
+    const PULL_UP: Option<u8> = None;
+}
+
+/// Checks that `LEN` lines fit on a single expander.
+struct PinCount<const LEN: usize>;
+
+impl<const LEN: usize> PinCount<LEN> {
+    const CHECK: () = assert!(LEN <= 16, "an expander has at most 16 lines");
+}
+
+/// Writes a 16-bit register pair.
+fn write_register<I2C>(
+    i2c: &mut I2C,
+    address: u8,
+    register: u8,
+    value: u16,
+) -> Result<(), I2C::Error>
+where
+    I2C: Write,
+{
+    let [low, high] = value.to_le_bytes();
+    i2c.write(address, &[register, low, high])
+}
+
+/// [`WriteLines`] on the pins of a port expander.
+///
+/// Line `i` is pin `i` of the expander. The selected line is driven low, and
+/// the rest of the lines are left floating as inputs, as if they were
+/// open-drain outputs. This means that a matrix without diodes cannot short
+/// two expander pins together.
+///
+/// [`init()`](Self::init) must be called before scanning.
+pub struct ExpanderOut<I2C, C> {
+    i2c: I2C,
+    address: u8,
+    _chip: PhantomData<C>,
+}
+
+impl<I2C, C> ExpanderOut<I2C, C>
+where
+    I2C: Write,
+    C: Chip,
+{
+    /// Creates write lines on the expander at the 7-bit I2C `address`.
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        Self {
+            i2c,
+            address,
+            _chip: PhantomData,
+        }
+    }
+
+    /// Configures the pins of the expander, releasing all of the lines.
+    pub fn init(&mut self) -> Result<(), I2C::Error> {
+        write_register(&mut self.i2c, self.address, C::DIRECTION, 0xffff)?;
+        write_register(&mut self.i2c, self.address, C::OUTPUT, 0x0000)
+    }
+
+    /// Releases the I2C bus.
+    pub fn free(self) -> I2C {
+        self.i2c
+    }
+}
+
+impl<I2C, C, const LEN: usize> WriteLines<LEN> for ExpanderOut<I2C, C>
+where
+    I2C: Write,
+    C: Chip,
+{
+    type Error = I2C::Error;
+
+    fn set(&mut self, index: usize) -> Result<(), Self::Error> {
+        let () = PinCount::<LEN>::CHECK;
+        write_register(&mut self.i2c, self.address, C::DIRECTION, !(1 << index))
+    }
+
+    fn set_all(&mut self) -> Result<bool, Self::Error> {
+        let () = PinCount::<LEN>::CHECK;
+        let lines = ((1u32 << LEN) - 1) as u16;
+        write_register(&mut self.i2c, self.address, C::DIRECTION, !lines)?;
+        Ok(true)
+    }
+}
+
+/// [`ReadLines`] on the pins of a port expander.
+///
+/// Line `i` is pin `i` of the expander. Like
+/// [`Direct`](crate::scanner::Direct), the lines are assumed to be pulled
+/// high, and driven low when connected to the selected write line.
+///
+/// All of the lines are sampled at once, when they are
+/// [latched](ReadLines::latch) by the scanner after selecting a write line.
+///
+/// [`init()`](Self::init) must be called before scanning.
+pub struct ExpanderIn<I2C, C> {
+    i2c: I2C,
+    address: u8,
+    sampled: u16,
+    _chip: PhantomData<C>,
+}
+
+impl<I2C, C> ExpanderIn<I2C, C>
+where
+    I2C: Write + WriteRead<Error = <I2C as Write>::Error>,
+    C: Chip,
+{
+    /// Creates read lines on the expander at the 7-bit I2C `address`.
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        Self {
+            i2c,
+            address,
+            sampled: 0,
+            _chip: PhantomData,
+        }
+    }
+
+    /// Configures the pins of the expander as inputs with pull-ups.
+    pub fn init(&mut self) -> Result<(), <I2C as Write>::Error> {
+        write_register(&mut self.i2c, self.address, C::DIRECTION, 0xffff)?;
+        if let Some(pull_up) = C::PULL_UP {
+            write_register(&mut self.i2c, self.address, pull_up, 0xffff)?;
+        }
+        Ok(())
+    }
+
+    /// Releases the I2C bus.
+    pub fn free(self) -> I2C {
+        self.i2c
+    }
+}
+
+impl<I2C, C, const LEN: usize> ReadLines<LEN> for ExpanderIn<I2C, C>
+where
+    I2C: Write + WriteRead<Error = <I2C as Write>::Error>,
+    C: Chip,
+{
+    type Error = <I2C as Write>::Error;
+
+    /// Reads the levels of all of the pins.
+    fn latch(&mut self) -> Result<(), Self::Error> {
+        let () = PinCount::<LEN>::CHECK;
+        let mut levels = [0; 2];
+        self.i2c
+            .write_read(self.address, &[C::INPUT], &mut levels)?;
+        self.sampled = u16::from_le_bytes(levels);
+        Ok(())
+    }
+
+    fn poll(&mut self, index: usize) -> Result<bool, Self::Error> {
+        let () = PinCount::<LEN>::CHECK;
+        Ok(self.sampled & (1 << index) == 0)
+    }
+
+    fn poll_all(&mut self) -> Result<ScanRow<LEN>, Self::Error>
+    where
+        [(); row_bytes(LEN)]:,
+    {
+        let () = PinCount::<LEN>::CHECK;
+        let mut lines = ScanRow::EMPTY;
+        for i in 0..LEN {
+            lines.set(i, self.sampled & (1 << i) == 0);
+        }
+        Ok(lines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    use embedded_hal_mock::i2c::{Mock, Transaction};
+    use std::vec;
+
+    #[test]
+    fn mcp23017_write_lines() {
+        let mut i2c = Mock::new(&[
+            Transaction::write(0x20, vec![0x00, 0xff, 0xff]),
+            Transaction::write(0x20, vec![0x14, 0x00, 0x00]),
+            Transaction::write(0x20, vec![0x00, 0xfe, 0xff]),
+            Transaction::write(0x20, vec![0x00, 0xff, 0xf7]),
+        ]);
+
+        let mut lines: ExpanderOut<_, Mcp23017> = ExpanderOut::new(i2c.clone(), 0x20);
+        lines.init().unwrap();
+        WriteLines::<12>::set(&mut lines, 0).unwrap();
+        WriteLines::<12>::set(&mut lines, 11).unwrap();
+
+        i2c.done();
+    }
+
+    #[test]
+    fn mcp23017_read_lines() {
+        let mut i2c = Mock::new(&[
+            Transaction::write(0x20, vec![0x00, 0xff, 0xff]),
+            Transaction::write(0x20, vec![0x0c, 0xff, 0xff]),
+            Transaction::write_read(0x20, vec![0x12], vec![0xfe, 0xf7]),
+            Transaction::write_read(0x20, vec![0x12], vec![0xff, 0xff]),
+        ]);
+
+        let mut lines: ExpanderIn<_, Mcp23017> = ExpanderIn::new(i2c.clone(), 0x20);
+        lines.init().unwrap();
+        // Both ports are read in one transaction when the lines are latched.
+        ReadLines::<12>::latch(&mut lines).unwrap();
+        assert!(ReadLines::<12>::poll(&mut lines, 0).unwrap());
+        assert!(!ReadLines::<12>::poll(&mut lines, 1).unwrap());
+        assert!(ReadLines::<12>::poll(&mut lines, 11).unwrap());
+        assert!(ReadLines::<12>::poll(&mut lines, 0).unwrap());
+        ReadLines::<12>::latch(&mut lines).unwrap();
+        assert!(!ReadLines::<12>::poll(&mut lines, 0).unwrap());
+
+        i2c.done();
+    }
+
+    #[test]
+    fn pca9555_registers() {
+        let mut i2c = Mock::new(&[
+            Transaction::write(0x21, vec![0x06, 0xff, 0xff]),
+            Transaction::write(0x21, vec![0x02, 0x00, 0x00]),
+            Transaction::write(0x21, vec![0x06, 0xff, 0xfd]),
+            Transaction::write(0x20, vec![0x06, 0xff, 0xff]),
+            Transaction::write_read(0x20, vec![0x00], vec![0xff, 0xfd]),
+        ]);
+
+        let mut write_lines: ExpanderOut<_, Pca9555> = ExpanderOut::new(i2c.clone(), 0x21);
+        let mut read_lines: ExpanderIn<_, Pca9555> = ExpanderIn::new(i2c.clone(), 0x20);
+        write_lines.init().unwrap();
+        WriteLines::<16>::set(&mut write_lines, 9).unwrap();
+        read_lines.init().unwrap();
+        ReadLines::<16>::latch(&mut read_lines).unwrap();
+        assert!(ReadLines::<16>::poll(&mut read_lines, 9).unwrap());
+
+        i2c.done();
+    }
+}
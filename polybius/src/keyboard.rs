@@ -1,4 +1,7 @@
-use crate::{backlight::Backlight, scanner::Scanner, uplink::Uplink};
+use crate::{
+    backlight::Backlight, encoder::Encoder, power::Power, scanner::Scanner, storage::Storage,
+    uplink::Uplink,
+};
 
 /// Collection of various features that may be provided by keyboard hardware.
 ///
@@ -23,10 +26,19 @@ pub trait Keyboard<const ROWS: usize, const COLS: usize> {
     type Scanner: Scanner<ROWS, COLS>;
     type Uplink: Uplink;
     type Backlight: Backlight;
+    type Encoder: Encoder;
+    type Power: Power;
+    type Storage: Storage;
 
     fn scanner(&mut self) -> &mut Self::Scanner;
 
     fn uplink(&mut self) -> &mut Self::Uplink;
 
     fn backlight(&mut self) -> &mut Self::Backlight;
+
+    fn encoder(&mut self) -> &mut Self::Encoder;
+
+    fn power(&mut self) -> &mut Self::Power;
+
+    fn storage(&mut self) -> &mut Self::Storage;
 }
@@ -21,9 +21,35 @@ pub enum Keycode {
     Hid(HidKeycode),
     System(SystemKeycode),
     Layer(LayerKeycode),
+    TapHold(TapHoldKeycode),
+    /// The tap dance at the given index in the keymap's
+    /// [`get_dance()`](crate::keymap::Keymap::get_dance).
+    TapDance(u8),
     User(u8),
 }
 
+impl Keycode {
+    /// Compares keycodes like `==`, but in const contexts.
+    pub const fn const_eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Hid(a), Self::Hid(b)) => *a as u8 == *b as u8,
+            (Self::System(a), Self::System(b)) => *a as u8 == *b as u8,
+            (Self::Layer(a), Self::Layer(b)) => a.0 == b.0,
+            (Self::TapHold(a), Self::TapHold(b)) => {
+                let same_hold = match (a.hold, b.hold) {
+                    (Hold::Modifiers(a), Hold::Modifiers(b)) | (Hold::Layer(a), Hold::Layer(b)) => {
+                        a == b
+                    }
+                    _ => false,
+                };
+                same_hold && a.tap as u8 == b.tap as u8
+            }
+            (Self::TapDance(a), Self::TapDance(b)) | (Self::User(a), Self::User(b)) => *a == *b,
+            _ => false,
+        }
+    }
+}
+
 impl From<SystemKeycode> for Keycode {
     fn from(v: SystemKeycode) -> Self {
         Self::System(v)
@@ -42,6 +68,12 @@ impl From<LayerKeycode> for Keycode {
     }
 }
 
+impl From<TapHoldKeycode> for Keycode {
+    fn from(v: TapHoldKeycode) -> Self {
+        Self::TapHold(v)
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub enum SystemKeycode {
     None,
@@ -79,6 +111,8 @@ pub enum LayerAction {
     Oneshot,
     Toggle,
     To,
+    Default,
+    PersistentDefault,
 }
 
 impl LayerAction {
@@ -87,6 +121,8 @@ impl LayerAction {
     const ONESHOT: u8 = 0x40;
     const TOGGLE: u8 = 0x60;
     const TO: u8 = 0x80;
+    const DEFAULT: u8 = 0xa0;
+    const PERSISTENT_DEFAULT: u8 = 0xc0;
 
     const fn code(&self) -> u8 {
         match self {
@@ -94,6 +130,8 @@ impl LayerAction {
             Self::Oneshot => Self::ONESHOT,
             Self::Toggle => Self::TOGGLE,
             Self::To => Self::TO,
+            Self::Default => Self::DEFAULT,
+            Self::PersistentDefault => Self::PERSISTENT_DEFAULT,
         }
     }
 
@@ -103,11 +141,46 @@ impl LayerAction {
             Self::ONESHOT => Self::Oneshot,
             Self::TOGGLE => Self::Toggle,
             Self::TO => Self::To,
+            Self::DEFAULT => Self::Default,
+            Self::PERSISTENT_DEFAULT => Self::PersistentDefault,
             _ => panic!(),
         }
     }
 }
 
+/// A key that sends a HID keycode when tapped, and does something else when
+/// held, as decided by the [`tap_hold`](crate::tap_hold) engine.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TapHoldKeycode {
+    hold: Hold,
+    tap: HidKeycode,
+}
+
+impl TapHoldKeycode {
+    pub const fn new(hold: Hold, tap: HidKeycode) -> Self {
+        Self { hold, tap }
+    }
+
+    pub const fn hold(&self) -> Hold {
+        self.hold
+    }
+
+    pub const fn tap(&self) -> HidKeycode {
+        self.tap
+    }
+}
+
+/// What a [`TapHoldKeycode`] does when held.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Hold {
+    /// Holds the modifiers in the given mask, with the same bits as the
+    /// modifier byte of a HID keyboard report (e.g.
+    /// [`MOD_LCTL`](qmk::MOD_LCTL)).
+    Modifiers(u8),
+    /// Enables the given layer, like a momentary layer key.
+    Layer(u8),
+}
+
 /// Keycodes from the USB HID Usage Tables, Keyboard/Keypad Page (0x07).
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(u8)]
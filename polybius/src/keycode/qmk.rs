@@ -2,16 +2,41 @@
 
 //! Aliases for keycodes based on the names used in QMK/TMK.
 
-use super::{HidKeycode, Keycode, LayerAction, LayerKeycode, SystemKeycode};
+use super::{HidKeycode, Hold, Keycode, LayerAction, LayerKeycode, SystemKeycode, TapHoldKeycode};
+
+pub const fn DF(layer: u8) -> Keycode {
+    Keycode::Layer(LayerKeycode::new(LayerAction::Default, layer))
+}
+
+/// Enables `layer` when held, and sends `kc` when tapped.
+pub const fn LT(layer: u8, kc: Keycode) -> Keycode {
+    assert!(layer & LayerKeycode::LAYER_MASK == layer);
+    Keycode::TapHold(TapHoldKeycode::new(Hold::Layer(layer), tap_keycode(kc)))
+}
 
 pub const fn MO(layer: u8) -> Keycode {
     Keycode::Layer(LayerKeycode::new(LayerAction::Momentary, layer))
 }
 
+/// Holds the modifiers `mods` (e.g. `MOD_LCTL | MOD_LSFT`) when held, and
+/// sends `kc` when tapped.
+pub const fn MT(mods: u8, kc: Keycode) -> Keycode {
+    Keycode::TapHold(TapHoldKeycode::new(Hold::Modifiers(mods), tap_keycode(kc)))
+}
+
 pub const fn OSL(layer: u8) -> Keycode {
     Keycode::Layer(LayerKeycode::new(LayerAction::Oneshot, layer))
 }
 
+pub const fn PDF(layer: u8) -> Keycode {
+    Keycode::Layer(LayerKeycode::new(LayerAction::PersistentDefault, layer))
+}
+
+/// The tap dance at `index` in the keymap's dances.
+pub const fn TD(index: u8) -> Keycode {
+    Keycode::TapDance(index)
+}
+
 pub const fn TG(layer: u8) -> Keycode {
     Keycode::Layer(LayerKeycode::new(LayerAction::Toggle, layer))
 }
@@ -20,6 +45,22 @@ pub const fn TO(layer: u8) -> Keycode {
     Keycode::Layer(LayerKeycode::new(LayerAction::To, layer))
 }
 
+const fn tap_keycode(kc: Keycode) -> HidKeycode {
+    match kc {
+        Keycode::Hid(hid) => hid,
+        _ => panic!("tap-hold keys can only tap HID keycodes"),
+    }
+}
+
+pub const MOD_LCTL: u8 = 0x01;
+pub const MOD_LSFT: u8 = 0x02;
+pub const MOD_LALT: u8 = 0x04;
+pub const MOD_LGUI: u8 = 0x08;
+pub const MOD_RCTL: u8 = 0x10;
+pub const MOD_RSFT: u8 = 0x20;
+pub const MOD_RALT: u8 = 0x40;
+pub const MOD_RGUI: u8 = 0x80;
+
 pub const KC_NO: Keycode = Keycode::System(SystemKeycode::None);
 pub const KC_TRANSPARENT: Keycode = Keycode::System(SystemKeycode::Transparent);
 pub const RESET: Keycode = Keycode::System(SystemKeycode::Reset);
@@ -1,15 +1,78 @@
 //! Mapping physical keys to keycodes.
 
+use crate::combo::{Combo, ComboTable};
+use crate::encoder::Direction;
 use crate::keycode::qmk::{KC_NO, KC_TRANSPARENT};
 use crate::keycode::{KeyAction, Keycode, LayerAction};
 use crate::system;
+use crate::time::{Duration, Instant};
 
 pub trait Keymap<const ROWS: usize, const COLS: usize> {
     fn get(&self, row: usize, col: usize) -> Keycode;
 
+    /// The keycode that is tapped when the encoder at `index` is turned by
+    /// one step in the given direction.
+    fn get_encoder(&self, index: usize, direction: Direction) -> Keycode {
+        let _ = (index, direction);
+        KC_NO
+    }
+
     fn key_event(&mut self, keycode: Keycode, action: KeyAction) {
         let _ = (keycode, action);
     }
+
+    /// Called periodically with the current time, before any key events
+    /// detected at that time are handled.
+    fn poll(&mut self, now: Instant) {
+        let _ = now;
+    }
+
+    /// Sets the default layer, which stays enabled underneath all other
+    /// layers. The system calls this with the default layer restored from
+    /// storage, if one was saved.
+    fn set_default_layer(&mut self, layer: u8) {
+        let _ = layer;
+    }
+
+    /// The tap dance referenced by [`Keycode::TapDance`] with the given
+    /// index.
+    fn get_dance(&self, index: u8) -> Option<Dance> {
+        let _ = index;
+        None
+    }
+
+    /// The combos that are active on the current layers.
+    fn combos(&self) -> &'static [Combo] {
+        &[]
+    }
+}
+
+/// A tap dance, which sends a different keycode depending on how many times
+/// its key is tapped in a row, and whether it is held on the last tap.
+///
+/// Keycodes can be handled by the keymap as usual, including
+/// [`Keycode::User`] keycodes to run custom code from
+/// [`key_event()`](Keymap::key_event).
+///
+/// # Example
+///
+/// ```ignore
+/// static DANCES: [Dance; 1] = [Dance {
+///     taps: &[KC_SCLN, KC_QUOT],
+///     holds: &[MO(1)],
+/// }];
+///
+/// let keymap = Layered::new(&LAYERS).with_dances(&DANCES);
+/// ```
+#[derive(Clone, Copy)]
+pub struct Dance {
+    /// The keycode tapped after one tap, two taps, and so on. Tapping more
+    /// times than this has keycodes for sends the last one.
+    pub taps: &'static [Keycode],
+    /// The keycode held when the key is held on the first tap, the second
+    /// tap, and so on. Where this has no keycode, the one from `taps` is held
+    /// instead.
+    pub holds: &'static [Keycode],
 }
 
 pub struct Simple<const ROWS: usize, const COLS: usize>(pub &'static [[Keycode; COLS]; ROWS]);
@@ -20,20 +83,110 @@ impl<const ROWS: usize, const COLS: usize> Keymap<ROWS, COLS> for Simple<ROWS, C
     }
 }
 
-pub struct Layered<const ROWS: usize, const COLS: usize, const LAYERS: usize> {
+/// The state of a one-shot layer key.
+#[derive(Clone, Copy)]
+enum Oneshot {
+    Inactive,
+    /// The one-shot key is held down. Once another key is pressed, it acts
+    /// like a momentary layer key instead.
+    Held {
+        layer: u8,
+        interrupted: bool,
+    },
+    /// The one-shot key was tapped, and the layer stays enabled until the
+    /// next key press.
+    Armed {
+        layer: u8,
+        since: Instant,
+    },
+    /// A key was pressed on the layer, which stays enabled until that key is
+    /// released.
+    Used {
+        layer: u8,
+        keycode: Keycode,
+    },
+    /// The one-shot key was tapped twice, which keeps the layer enabled until
+    /// it is tapped again.
+    Locked {
+        layer: u8,
+    },
+}
+
+/// A keymap with several layers, which can be enabled and disabled by layer
+/// keycodes.
+///
+/// Separately, one of the layers is the default layer (initially layer 0),
+/// which stays enabled even when [`TO()`](crate::keycode::qmk::TO) switches
+/// to another layer. It can be changed with
+/// [`DF()`](crate::keycode::qmk::DF), or with
+/// [`PDF()`](crate::keycode::qmk::PDF) to also save it to the keyboard's
+/// storage.
+///
+/// Each of the `ENCODERS` encoders also has a pair of keycodes per layer,
+/// tapped when it is turned clockwise and counter-clockwise, respectively.
+pub struct Layered<
+    const ROWS: usize,
+    const COLS: usize,
+    const LAYERS: usize,
+    const ENCODERS: usize = 0,
+> {
     layer_mask: u32,
+    default_layer_mask: u32,
     layers: &'static [[[Keycode; COLS]; ROWS]; LAYERS],
+    encoders: &'static [[[Keycode; 2]; ENCODERS]],
+    dances: &'static [Dance],
+    combos: ComboTable<ROWS, COLS>,
+    oneshot: Oneshot,
+    oneshot_timeout: Option<Duration>,
+    now: Instant,
 }
 
 impl<const ROWS: usize, const COLS: usize, const LAYERS: usize> Layered<ROWS, COLS, LAYERS> {
     pub fn new(layers: &'static [[[Keycode; COLS]; ROWS]; LAYERS]) -> Self {
+        Self::with_encoders(layers, &[[]; LAYERS])
+    }
+}
+
+impl<const ROWS: usize, const COLS: usize, const LAYERS: usize, const ENCODERS: usize>
+    Layered<ROWS, COLS, LAYERS, ENCODERS>
+{
+    pub fn with_encoders(
+        layers: &'static [[[Keycode; COLS]; ROWS]; LAYERS],
+        encoders: &'static [[[Keycode; 2]; ENCODERS]; LAYERS],
+    ) -> Self {
         Self {
-            layer_mask: 1,
+            layer_mask: 0,
+            default_layer_mask: 1,
             layers,
+            encoders,
+            dances: &[],
+            combos: ComboTable::EMPTY,
+            oneshot: Oneshot::Inactive,
+            oneshot_timeout: None,
+            now: Instant::from_ticks(0),
         }
     }
+
+    /// Sets the tap dances, referenced by their index in `dances`.
+    pub fn with_dances(self, dances: &'static [Dance]) -> Self {
+        Self { dances, ..self }
+    }
+
+    /// Sets the combos of each layer, starting from layer 0. Layers without
+    /// combos use the combos of the highest enabled layer below them that has
+    /// any.
+    pub fn with_combos(self, combos: ComboTable<ROWS, COLS>) -> Self {
+        Self { combos, ..self }
+    }
+
+    /// Sets how long a tapped one-shot layer waits for the next key press
+    /// before it is disabled. `None`, the default, waits forever.
+    pub fn set_oneshot_timeout(&mut self, timeout: Option<Duration>) {
+        self.oneshot_timeout = timeout;
+    }
+
     pub fn is_layer_enabled(&self, layer: u8) -> bool {
-        (self.layer_mask & (1 << layer)) != 0
+        ((self.layer_mask | self.default_layer_mask) & (1 << layer)) != 0
     }
 
     pub fn enable_layer(&mut self, layer: u8) {
@@ -50,17 +203,91 @@ impl<const ROWS: usize, const COLS: usize, const LAYERS: usize> Layered<ROWS, CO
         self.layer_mask ^= 1 << layer;
         system::clear_keyboard_but_mods();
     }
-}
 
-impl<const ROWS: usize, const COLS: usize, const LAYERS: usize> Keymap<ROWS, COLS>
-    for Layered<ROWS, COLS, LAYERS>
-{
-    fn get(&self, row: usize, col: usize) -> Keycode {
+    /// Disables the active one-shot layer, if any.
+    fn cancel_oneshot(&mut self) {
+        match self.oneshot {
+            Oneshot::Inactive => {}
+            Oneshot::Held { layer, .. }
+            | Oneshot::Armed { layer, .. }
+            | Oneshot::Used { layer, .. }
+            | Oneshot::Locked { layer } => {
+                self.disable_layer(layer);
+            }
+        }
+        self.oneshot = Oneshot::Inactive;
+    }
+
+    /// Handles an event of a one-shot layer key.
+    ///
+    /// Holding the key enables the layer like a momentary layer key. If no
+    /// other key is pressed before it is released, the layer stays enabled
+    /// for the next key press, and tapping it again instead locks the layer
+    /// until the next tap.
+    fn oneshot_event(&mut self, layer: u8, action: KeyAction) {
+        match (self.oneshot, action) {
+            (Oneshot::Armed { layer: armed, .. }, KeyAction::Pressed) if armed == layer => {
+                self.oneshot = Oneshot::Locked { layer };
+            }
+            (Oneshot::Locked { layer: locked }, KeyAction::Pressed) if locked == layer => {
+                self.cancel_oneshot();
+            }
+            (_, KeyAction::Pressed) => {
+                self.cancel_oneshot();
+                self.enable_layer(layer);
+                self.oneshot = Oneshot::Held {
+                    layer,
+                    interrupted: false,
+                };
+            }
+            (
+                Oneshot::Held {
+                    layer: held,
+                    interrupted,
+                },
+                KeyAction::Released,
+            ) if held == layer => {
+                if interrupted {
+                    self.cancel_oneshot();
+                } else {
+                    self.oneshot = Oneshot::Armed {
+                        layer,
+                        since: self.now,
+                    };
+                }
+            }
+            (_, KeyAction::Released) => {}
+        }
+    }
+
+    /// Handles an event of any key other than a layer key, which may use up
+    /// the active one-shot layer.
+    fn oneshot_other_event(&mut self, keycode: Keycode, action: KeyAction) {
+        match (self.oneshot, action) {
+            (Oneshot::Held { layer, .. }, KeyAction::Pressed) => {
+                self.oneshot = Oneshot::Held {
+                    layer,
+                    interrupted: true,
+                };
+            }
+            (Oneshot::Armed { layer, .. }, KeyAction::Pressed) => {
+                self.oneshot = Oneshot::Used { layer, keycode };
+            }
+            (Oneshot::Used { keycode: used, .. }, KeyAction::Released) if used == keycode => {
+                self.cancel_oneshot();
+            }
+            _ => {}
+        }
+    }
+
+    /// Finds the keycode on the highest enabled layer that isn't transparent,
+    /// given the keycode on each layer.
+    fn lookup(&self, keycode: impl Fn(usize) -> Keycode) -> Keycode {
         for i in (0..LAYERS).rev() {
             if !self.is_layer_enabled(i as u8) {
                 continue;
             }
-            match self.layers[i][row][col] {
+            match keycode(i) {
                 KC_TRANSPARENT => {
                     continue;
                 }
@@ -71,6 +298,27 @@ impl<const ROWS: usize, const COLS: usize, const LAYERS: usize> Keymap<ROWS, COL
         }
         KC_NO
     }
+}
+
+impl<const ROWS: usize, const COLS: usize, const LAYERS: usize, const ENCODERS: usize>
+    Keymap<ROWS, COLS> for Layered<ROWS, COLS, LAYERS, ENCODERS>
+{
+    fn get(&self, row: usize, col: usize) -> Keycode {
+        self.lookup(|layer| self.layers[layer][row][col])
+    }
+
+    fn get_encoder(&self, index: usize, direction: Direction) -> Keycode {
+        let turn = match direction {
+            Direction::Clockwise => 0,
+            Direction::CounterClockwise => 1,
+        };
+        self.lookup(|layer| {
+            self.encoders
+                .get(layer)
+                .and_then(|encoders| encoders.get(index))
+                .map_or(KC_NO, |encoder| encoder[turn])
+        })
+    }
 
     fn key_event(&mut self, keycode: Keycode, action: KeyAction) {
         match keycode {
@@ -83,7 +331,7 @@ impl<const ROWS: usize, const COLS: usize, const LAYERS: usize> Keymap<ROWS, COL
                     }
                 }
                 LayerAction::Oneshot => {
-                    //TODO
+                    self.oneshot_event(layer_key.layer(), action);
                 }
                 LayerAction::Toggle => {
                     if action.is_pressed() {
@@ -92,12 +340,160 @@ impl<const ROWS: usize, const COLS: usize, const LAYERS: usize> Keymap<ROWS, COL
                 }
                 LayerAction::To => {
                     if action.is_pressed() {
-                        // TODO preserve default layers
                         self.layer_mask = 1 << layer_key.layer();
                     }
                 }
+                LayerAction::Default => {
+                    if action.is_pressed() {
+                        self.set_default_layer(layer_key.layer());
+                    }
+                }
+                LayerAction::PersistentDefault => {
+                    if action.is_pressed() {
+                        self.set_default_layer(layer_key.layer());
+                        system::save_default_layer(layer_key.layer());
+                    }
+                }
             },
-            _ => {}
+            other => {
+                self.oneshot_other_event(other, action);
+            }
         }
     }
+
+    fn poll(&mut self, now: Instant) {
+        self.now = now;
+        if let (Oneshot::Armed { since, .. }, Some(timeout)) = (self.oneshot, self.oneshot_timeout)
+        {
+            let expired = now
+                .checked_duration_since(since)
+                .is_none_or(|elapsed| elapsed >= timeout);
+            if expired {
+                self.cancel_oneshot();
+            }
+        }
+    }
+
+    fn get_dance(&self, index: u8) -> Option<Dance> {
+        self.dances.get(usize::from(index)).copied()
+    }
+
+    fn combos(&self) -> &'static [Combo] {
+        (0..LAYERS)
+            .rev()
+            .filter(|&layer| self.is_layer_enabled(layer as u8))
+            .filter_map(|layer| self.combos.get(layer))
+            .find(|combos| !combos.is_empty())
+            .unwrap_or(&[])
+    }
+
+    fn set_default_layer(&mut self, layer: u8) {
+        // A saved layer might not exist anymore, after changing the keymap.
+        if usize::from(layer) < LAYERS {
+            self.default_layer_mask = 1 << layer;
+            system::clear_keyboard_but_mods();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keycode::qmk::{DF, KC_A, KC_B, KC_TRNS, OSL, TO};
+
+    static LAYERS: [[[Keycode; 3]; 1]; 2] = [[[OSL(1), KC_A, KC_A]], [[KC_TRNS, KC_B, KC_B]]];
+
+    /// Presses or releases the key at `col`, like the system does.
+    fn event(keymap: &mut Layered<1, 3, 2>, col: usize, action: KeyAction) -> Keycode {
+        let keycode = keymap.get(0, col);
+        keymap.key_event(keycode, action);
+        keycode
+    }
+
+    fn tap(keymap: &mut Layered<1, 3, 2>, col: usize) -> Keycode {
+        let keycode = event(keymap, col, KeyAction::Pressed);
+        event(keymap, col, KeyAction::Released);
+        keycode
+    }
+
+    #[test]
+    fn oneshot_tap() {
+        let mut keymap = Layered::new(&LAYERS);
+        tap(&mut keymap, 0);
+        assert!(keymap.is_layer_enabled(1));
+
+        // The layer stays enabled until the next key is released.
+        assert!(event(&mut keymap, 1, KeyAction::Pressed) == KC_B);
+        assert!(event(&mut keymap, 1, KeyAction::Released) == KC_B);
+        assert!(!keymap.is_layer_enabled(1));
+        assert!(tap(&mut keymap, 2) == KC_A);
+    }
+
+    #[test]
+    fn oneshot_hold() {
+        let mut keymap = Layered::new(&LAYERS);
+        event(&mut keymap, 0, KeyAction::Pressed);
+        assert!(tap(&mut keymap, 1) == KC_B);
+        assert!(tap(&mut keymap, 2) == KC_B);
+        event(&mut keymap, 0, KeyAction::Released);
+        assert!(!keymap.is_layer_enabled(1));
+    }
+
+    #[test]
+    fn oneshot_timeout() {
+        let mut keymap = Layered::new(&LAYERS);
+        keymap.set_oneshot_timeout(Some(Duration::millis(100)));
+        keymap.poll(Instant::from_ticks(1000));
+        tap(&mut keymap, 0);
+        keymap.poll(Instant::from_ticks(1099));
+        assert!(keymap.is_layer_enabled(1));
+        keymap.poll(Instant::from_ticks(1100));
+        assert!(!keymap.is_layer_enabled(1));
+    }
+
+    #[test]
+    fn oneshot_lock() {
+        let mut keymap = Layered::new(&LAYERS);
+        tap(&mut keymap, 0);
+        tap(&mut keymap, 0);
+        assert!(tap(&mut keymap, 1) == KC_B);
+        assert!(tap(&mut keymap, 2) == KC_B);
+
+        tap(&mut keymap, 0);
+        assert!(!keymap.is_layer_enabled(1));
+    }
+
+    #[test]
+    fn default_layer() {
+        let mut keymap = Layered::new(&LAYERS);
+        keymap.key_event(DF(1), KeyAction::Pressed);
+        keymap.key_event(DF(1), KeyAction::Released);
+        assert!(!keymap.is_layer_enabled(0));
+        assert!(keymap.get(0, 0) == KC_NO);
+        assert!(keymap.get(0, 1) == KC_B);
+
+        // Switching layers keeps the default layer enabled.
+        keymap.key_event(TO(0), KeyAction::Pressed);
+        assert!(keymap.is_layer_enabled(0));
+        assert!(keymap.is_layer_enabled(1));
+
+        // Layers that don't exist are ignored.
+        keymap.set_default_layer(2);
+        assert!(keymap.is_layer_enabled(1));
+    }
+
+    #[test]
+    fn encoders() {
+        static ENCODERS: [[[Keycode; 2]; 1]; 2] = [[[KC_A, KC_B]], [[KC_TRNS, KC_A]]];
+        let mut keymap = Layered::with_encoders(&LAYERS, &ENCODERS);
+        assert!(keymap.get_encoder(0, Direction::Clockwise) == KC_A);
+
+        keymap.key_event(TO(1), KeyAction::Pressed);
+        assert!(keymap.get_encoder(0, Direction::Clockwise) == KC_A);
+        assert!(keymap.get_encoder(0, Direction::CounterClockwise) == KC_A);
+
+        // Encoders that don't exist do nothing.
+        assert!(keymap.get_encoder(1, Direction::Clockwise) == KC_NO);
+        assert!(Layered::new(&LAYERS).get_encoder(0, Direction::Clockwise) == KC_NO);
+    }
 }
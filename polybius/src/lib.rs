@@ -1,16 +1,32 @@
 #![no_std]
+#![feature(const_trait_impl)]
 #![feature(generic_const_exprs)]
 #![deny(unsafe_op_in_unsafe_fn)]
 
+pub mod analog;
+pub mod background;
 pub mod backlight;
+pub mod combo;
+pub mod composite;
+pub mod debounce;
+pub mod diagnostics;
 pub mod diodes;
+pub mod ec;
+pub mod encoder;
+pub mod expander;
 pub mod keyboard;
 pub mod keycode;
 pub mod keymap;
 pub mod mutex;
 pub mod pin_group;
+pub mod power;
 pub mod scanner;
+pub mod shift_register;
+pub mod split;
+pub mod storage;
 pub mod system;
+pub mod tap_hold;
+pub mod time;
 pub mod uplink;
 
 pub mod arch;
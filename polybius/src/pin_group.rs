@@ -1,5 +1,6 @@
 //! Logical groups of pins.
 
+use crate::scanner::{row_bytes, ScanRow};
 use embedded_hal::digital::v2::{InputPin, OutputPin};
 
 /// A fixed-size group of output pins.
@@ -24,6 +25,23 @@ pub trait InputGroup<const LEN: usize> {
     fn is_low(&self, index: usize) -> Result<bool, Self::Error>;
 
     fn is_high(&self, index: usize) -> Result<bool, Self::Error>;
+
+    /// Reads all of the pins at once, returning the set of pins that are low.
+    ///
+    /// By default, this reads each pin individually. Groups that can read
+    /// several pins at once, like [`PortPins`], override this to read fewer
+    /// registers; whether that makes a scan measurably faster depends on the
+    /// target, and hasn't been measured.
+    fn read_low(&self) -> Result<ScanRow<LEN>, Self::Error>
+    where
+        [(); row_bytes(LEN)]:,
+    {
+        let mut low = ScanRow::EMPTY;
+        for i in 0..LEN {
+            low.set(i, self.is_low(i)?);
+        }
+        Ok(low)
+    }
 }
 
 impl<T, const LEN: usize> OutputGroup<LEN> for [T; LEN]
@@ -165,10 +183,84 @@ tuple_impls! {
     (A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8, J: 9, K: 10, L: 11, M: 12, N: 13, O: 14, P: 15, Q: 16, R: 17, S: 18, T: 19): 20,
 }
 
+/// A GPIO port, whose pins can all be read at once.
+pub trait InputPort {
+    type Error;
+
+    /// Reads the levels of all of the pins of the port, as a bitmask with a
+    /// set bit for each pin that is high.
+    fn read(&self) -> Result<u8, Self::Error>;
+}
+
+/// A group of input pins spread over one or more GPIO ports.
+///
+/// When reading the whole group with [`read_low()`](InputGroup::read_low),
+/// each port is only read once, no matter how many pins of the group it has.
+///
+/// The pins themselves are not managed by this group; they must be configured
+/// as inputs beforehand.
+pub struct PortPins<P, const PORTS: usize, const LEN: usize> {
+    ports: [P; PORTS],
+    pins: [(usize, u8); LEN],
+}
+
+impl<P, const PORTS: usize, const LEN: usize> PortPins<P, PORTS, LEN>
+where
+    P: InputPort,
+{
+    /// Creates a group from the ports it reads, and the position of each pin
+    /// as a pair of the index of its port in `ports` and its bit number in
+    /// that port.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any pin's port index or bit number is out of range.
+    pub fn new(ports: [P; PORTS], pins: [(usize, u8); LEN]) -> Self {
+        for (port, bit) in pins {
+            assert!(port < PORTS && bit < 8, "pin out of range");
+        }
+        Self { ports, pins }
+    }
+}
+
+impl<P, const PORTS: usize, const LEN: usize> InputGroup<LEN> for PortPins<P, PORTS, LEN>
+where
+    P: InputPort,
+{
+    type Error = P::Error;
+
+    fn is_low(&self, index: usize) -> Result<bool, Self::Error> {
+        self.is_high(index).map(|high| !high)
+    }
+
+    fn is_high(&self, index: usize) -> Result<bool, Self::Error> {
+        let (port, bit) = self.pins[index];
+        Ok(self.ports[port].read()? & (1 << bit) != 0)
+    }
+
+    fn read_low(&self) -> Result<ScanRow<LEN>, Self::Error>
+    where
+        [(); row_bytes(LEN)]:,
+    {
+        let mut levels = [0; PORTS];
+        for (level, port) in levels.iter_mut().zip(&self.ports) {
+            *level = port.read()?;
+        }
+
+        let mut low = ScanRow::EMPTY;
+        for (i, &(port, bit)) in self.pins.iter().enumerate() {
+            low.set(i, levels[port] & (1 << bit) == 0);
+        }
+        Ok(low)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use core::cell::Cell;
+    use core::convert::Infallible;
     use embedded_hal_mock::pin::{Mock, State, Transaction};
 
     #[test]
@@ -260,4 +352,82 @@ mod tests {
         pin_b.done();
         pin_c.done();
     }
+
+    struct FakePort {
+        levels: u8,
+        reads: Cell<usize>,
+    }
+
+    impl InputPort for &FakePort {
+        type Error = Infallible;
+
+        fn read(&self) -> Result<u8, Self::Error> {
+            self.reads.set(self.reads.get() + 1);
+            Ok(self.levels)
+        }
+    }
+
+    /// The read lines of the Planck rev2, spread over four ports.
+    fn planck_pins<P: InputPort>(ports: [P; 4]) -> PortPins<P, 4, 12> {
+        PortPins::new(
+            ports,
+            [
+                (3, 1),
+                (3, 0),
+                (0, 0),
+                (1, 7),
+                (3, 4),
+                (3, 5),
+                (3, 6),
+                (3, 7),
+                (2, 4),
+                (2, 6),
+                (0, 4),
+                (2, 7),
+            ],
+        )
+    }
+
+    #[test]
+    fn port_pins_read_count() {
+        let ports: [FakePort; 4] = core::array::from_fn(|_| FakePort {
+            levels: 0xff,
+            reads: Cell::new(0),
+        });
+        let total_reads = || ports.iter().map(|port| port.reads.get()).sum::<usize>();
+        let group = planck_pins([&ports[0], &ports[1], &ports[2], &ports[3]]);
+
+        // Reading the pins one by one reads a port for every pin...
+        for i in 0..12 {
+            assert!(!group.is_low(i).unwrap());
+        }
+        assert_eq!(total_reads(), 12);
+
+        // ...while reading them all at once reads each port once.
+        assert!(group.read_low().unwrap() == ScanRow::EMPTY);
+        assert_eq!(total_reads(), 16);
+    }
+
+    #[test]
+    fn port_pins_read_low() {
+        let port_a = FakePort {
+            levels: 0b1111_1011,
+            reads: Cell::new(0),
+        };
+        let port_b = FakePort {
+            levels: 0b0111_1111,
+            reads: Cell::new(0),
+        };
+
+        let group = PortPins::new([&port_a, &port_b], [(1, 7), (0, 1), (0, 2), (1, 0)]);
+        let low = group.read_low().unwrap();
+
+        // Each port is read once for the whole group.
+        assert_eq!(port_a.reads.get(), 1);
+        assert_eq!(port_b.reads.get(), 1);
+        assert!(low.get(0));
+        assert!(!low.get(1));
+        assert!(low.get(2));
+        assert!(!low.get(3));
+    }
 }
@@ -0,0 +1,43 @@
+//! Power management.
+
+use core::convert::Infallible;
+
+/// The reason for going to sleep, which determines how deeply the keyboard
+/// can sleep.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SleepMode {
+    /// No keys have been pressed for the idle timeout, but the host is still
+    /// active; the uplink must keep responding to the host while sleeping.
+    Idle,
+    /// The host has suspended the uplink; only a key press or the host
+    /// resuming needs to wake up the keyboard.
+    Suspend,
+}
+
+/// Power management interface for keyboard hardware.
+///
+/// For keyboards that do not support sleeping, the type [`NoPower`] provides a
+/// no-op implementation.
+pub trait Power {
+    type Error;
+
+    /// Puts the microcontroller to sleep, returning after it wakes up.
+    ///
+    /// Before this is called, the scanner has been prepared with
+    /// [`prepare_wake()`](crate::scanner::Scanner::prepare_wake), so any key
+    /// press is visible on its read lines; the implementation should arm
+    /// interrupts (e.g. pin change interrupts) on those lines to wake up.
+    fn sleep(&mut self, mode: SleepMode) -> Result<(), Self::Error>;
+}
+
+/// A no-op power implementation that never sleeps.
+pub struct NoPower;
+
+impl Power for NoPower {
+    type Error = Infallible;
+
+    fn sleep(&mut self, mode: SleepMode) -> Result<(), Self::Error> {
+        let _ = mode;
+        Ok(())
+    }
+}
@@ -3,16 +3,22 @@
 //! Defines how the key switches are wired up and how to scan
 //! those switches for their press state.
 
+use crate::debounce::Debouncer;
 use crate::diodes::{DiodeConfiguration, KeyPosition, ScanPosition};
+use crate::keycode::KeyAction;
 use crate::pin_group::{InputGroup, OutputGroup};
+use crate::time::Instant;
 use core::marker::PhantomData;
+use core::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, BitXor, BitXorAssign, Not};
 
 /// Scans keys and keeps track of which ones are pressed.
 pub trait Scanner<const ROWS: usize, const COLS: usize> {
     type Error;
 
     /// Scans the keys and updates the internal list of pressed keys.
-    fn poll(&mut self) -> Result<(), Self::Error>;
+    ///
+    /// `now` is the current time, which is used for debouncing.
+    fn poll(&mut self, now: Instant) -> Result<(), Self::Error>;
 
     /// Whether the given logical key position is currently held down.
     fn is_pressed(&self, row: usize, col: usize) -> bool;
@@ -24,94 +30,737 @@ pub trait Scanner<const ROWS: usize, const COLS: usize> {
     /// Whether the given logical key position was "just released" (transitioned
     /// from pressed to released, as of the last call to [`poll()`]).
     fn just_released(&self, row: usize, col: usize) -> bool;
+
+    /// Whether ghosting was detected during the last call to [`poll()`].
+    ///
+    /// While this is true, the state of some keys could not be determined, and
+    /// those keys have been held in their previous state.
+    fn ghosted(&self) -> bool {
+        false
+    }
+
+    /// Prepares the scanner for sleeping until a key is pressed, by making any
+    /// key press visible on the read lines at once (e.g. by driving all of the
+    /// write lines active), so that the read lines can wake up the
+    /// microcontroller.
+    ///
+    /// Returns false if the scanner does not support this, which is the
+    /// default. Normal scanning resumes with the next call to [`poll()`].
+    fn prepare_wake(&mut self) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
+    /// Whether every key change read during the last call to [`poll()`] has
+    /// been reported, with none still being held back (e.g. by a debouncer).
+    ///
+    /// The system does not go to sleep while this is false. This is true by
+    /// default.
+    fn is_idle(&self) -> bool {
+        true
+    }
+
+    /// Calls `f` with an event for each key that was just pressed or released
+    /// as of the last call to [`poll()`], stamped with `timestamp`.
+    ///
+    /// Events are emitted in the order that the keys were scanned, if the
+    /// scanner has such an order; the default implementation emits them in
+    /// row-major order.
+    fn for_each_event<F>(&self, timestamp: Instant, mut f: F)
+    where
+        F: FnMut(KeyEvent),
+    {
+        for row in 0..ROWS {
+            for col in 0..COLS {
+                let action = if self.just_pressed(row, col) {
+                    KeyAction::Pressed
+                } else if self.just_released(row, col) {
+                    KeyAction::Released
+                } else {
+                    continue;
+                };
+                f(KeyEvent {
+                    row,
+                    col,
+                    action,
+                    timestamp,
+                });
+            }
+        }
+    }
+}
+
+/// A key being pressed or released.
+#[derive(Clone, Copy, PartialEq)]
+pub struct KeyEvent {
+    pub row: usize,
+    pub col: usize,
+    pub action: KeyAction,
+    /// The time of the scan that detected the change.
+    pub timestamp: Instant,
+}
+
+/// The number of bytes needed to store a [`ScanRow`] of `len` keys.
+pub const fn row_bytes(len: usize) -> usize {
+    len.div_ceil(8)
+}
+
+/// The pressed state of a row of `LEN` keys, stored as a bitset.
+///
+/// The storage is sized from `LEN` at compile time, so a row can be as wide as
+/// the matrix needs it to be, and every key index below `LEN` is guaranteed to
+/// fit.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ScanRow<const LEN: usize>
+where
+    [(); row_bytes(LEN)]:,
+{
+    bytes: [u8; row_bytes(LEN)],
+}
+
+impl<const LEN: usize> ScanRow<LEN>
+where
+    [(); row_bytes(LEN)]:,
+{
+    /// A row with no keys pressed.
+    pub const EMPTY: Self = Self {
+        bytes: [0; row_bytes(LEN)],
+    };
+
+    /// Mask of the bits in the last byte that correspond to keys in the row.
+    const LAST_BYTE_MASK: u8 = match LEN % 8 {
+        0 => 0xff,
+        n => (1 << n) - 1,
+    };
+
+    /// Whether the key at `index` is pressed.
+    pub fn get(&self, index: usize) -> bool {
+        assert!(index < LEN, "index out of bounds");
+        self.bytes[index / 8] & (1 << (index % 8)) != 0
+    }
+
+    /// Sets whether the key at `index` is pressed.
+    pub fn set(&mut self, index: usize, pressed: bool) {
+        assert!(index < LEN, "index out of bounds");
+        if pressed {
+            self.bytes[index / 8] |= 1 << (index % 8);
+        } else {
+            self.bytes[index / 8] &= !(1 << (index % 8));
+        }
+    }
+
+    /// Flips the state of the key at `index`.
+    pub fn toggle(&mut self, index: usize) {
+        assert!(index < LEN, "index out of bounds");
+        self.bytes[index / 8] ^= 1 << (index % 8);
+    }
+
+    /// Whether no keys are pressed.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.iter().all(|&byte| byte == 0)
+    }
+
+    /// The number of keys that are pressed.
+    pub fn count(&self) -> usize {
+        self.bytes
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+}
+
+impl<const LEN: usize> Default for ScanRow<LEN>
+where
+    [(); row_bytes(LEN)]:,
+{
+    fn default() -> Self {
+        Self::EMPTY
+    }
+}
+
+impl<const LEN: usize> Not for ScanRow<LEN>
+where
+    [(); row_bytes(LEN)]:,
+{
+    type Output = Self;
+
+    fn not(mut self) -> Self {
+        for byte in &mut self.bytes {
+            *byte = !*byte;
+        }
+        // Keep the unused bits clear, so rows can be compared directly.
+        if let Some(last) = self.bytes.last_mut() {
+            *last &= Self::LAST_BYTE_MASK;
+        }
+        self
+    }
+}
+
+macro_rules! bitwise_impls {
+    ($($op:ident::$method:ident, $op_assign:ident::$method_assign:ident;)*) => {$(
+        impl<const LEN: usize> $op for ScanRow<LEN>
+        where
+            [(); row_bytes(LEN)]:,
+        {
+            type Output = Self;
+
+            fn $method(mut self, rhs: Self) -> Self {
+                self.$method_assign(rhs);
+                self
+            }
+        }
+
+        impl<const LEN: usize> $op_assign for ScanRow<LEN>
+        where
+            [(); row_bytes(LEN)]:,
+        {
+            fn $method_assign(&mut self, rhs: Self) {
+                for (byte, rhs) in self.bytes.iter_mut().zip(rhs.bytes) {
+                    byte.$method_assign(rhs);
+                }
+            }
+        }
+    )*};
 }
 
-type ScanRow = u32;
+bitwise_impls! {
+    BitAnd::bitand, BitAndAssign::bitand_assign;
+    BitOr::bitor, BitOrAssign::bitor_assign;
+    BitXor::bitxor, BitXorAssign::bitxor_assign;
+}
 
 /// An implementation of a "scan matrix".
-pub struct ScanMatrix<W, R, D, C, const ROWS: usize, const COLS: usize> {
+///
+/// The raw key state read from the matrix on each poll is filtered through the
+/// debouncer `B` before being reported.
+///
+/// # Ghosting
+///
+/// If the diode configuration indicates that the matrix [can
+/// ghost](DiodeConfiguration::CAN_GHOST), the scanner will look for the
+/// telltale rectangle pattern: two write lines that both appear connected to
+/// the same two (or more) read lines. When three of the four keys on the
+/// corners of such a rectangle are pressed, the fourth one reads as pressed
+/// too, and there is no way to tell which one of them is the phantom. All of
+/// the keys on those corners are held in their previous state until the
+/// rectangle is broken up, and [`ghosted()`](Scanner::ghosted) reports true in
+/// the meantime.
+pub struct ScanMatrix<W, R, D, C, B, const ROWS: usize, const COLS: usize>
+where
+    [(); row_bytes(COLS)]:,
+{
     write_lines: W,
     read_lines: R,
     scan_delay: D,
-    _diodes: PhantomData<C>,
-    old_state: [ScanRow; ROWS],
-    new_state: [ScanRow; ROWS],
+    state: MatrixState<C, B, ROWS, COLS>,
 }
 
-impl<W, R, D, C, const ROWS: usize, const COLS: usize> ScanMatrix<W, R, D, C, ROWS, COLS>
+impl<W, R, D, C, B, const ROWS: usize, const COLS: usize> ScanMatrix<W, R, D, C, B, ROWS, COLS>
 where
     C: DiodeConfiguration<ROWS, COLS>,
     W: WriteLines<{ C::WRITE_LINES }>,
     R: ReadLines<{ C::READ_LINES }, Error = W::Error>,
     D: FnMut(),
+    B: Debouncer<ROWS, COLS>,
+    [(); row_bytes(COLS)]:,
 {
-    pub fn new(write_lines: W, read_lines: R, scan_delay: D) -> Self {
+    pub fn new(write_lines: W, read_lines: R, scan_delay: D, debouncer: B) -> Self {
         Self {
             write_lines,
             read_lines,
             scan_delay,
-            _diodes: PhantomData,
-            old_state: [Default::default(); ROWS],
-            new_state: [Default::default(); ROWS],
+            state: MatrixState::new(debouncer),
         }
     }
 }
 
-impl<W, R, D, C, const ROWS: usize, const COLS: usize> Scanner<ROWS, COLS>
-    for ScanMatrix<W, R, D, C, ROWS, COLS>
+impl<W, R, D, C, B, const ROWS: usize, const COLS: usize> Scanner<ROWS, COLS>
+    for ScanMatrix<W, R, D, C, B, ROWS, COLS>
 where
     C: DiodeConfiguration<ROWS, COLS>,
     W: WriteLines<{ C::WRITE_LINES }>,
     R: ReadLines<{ C::READ_LINES }, Error = W::Error>,
     D: FnMut(),
+    B: Debouncer<ROWS, COLS>,
+    [(); row_bytes(COLS)]:,
+    [(); row_bytes(C::READ_LINES)]:,
+    [(); C::WRITE_LINES]:,
 {
     type Error = W::Error;
 
-    fn poll(&mut self) -> Result<(), Self::Error> {
-        self.old_state = self.new_state;
-        self.new_state = [Default::default(); ROWS];
-
-        //TODO ghosting
-        for i in 0..C::WRITE_LINES {
+    fn poll(&mut self, now: Instant) -> Result<(), Self::Error> {
+        // Read lines that are connected to each write line.
+        let mut scan_lines = [ScanRow::<{ C::READ_LINES }>::EMPTY; C::WRITE_LINES];
+        for (i, scan_line) in scan_lines.iter_mut().enumerate() {
             self.write_lines.set(i)?;
             (self.scan_delay)();
+            self.read_lines.latch()?;
+            let lines = self.read_lines.poll_all()?;
             for j in 0..C::READ_LINES {
-                if self.read_lines.poll(j)? {
-                    let KeyPosition { row, col } = C::key_position(ScanPosition {
-                        write_index: i,
-                        read_index: j,
-                    });
+                scan_line.set(j, is_key::<C, ROWS, COLS>(i, j) && lines.get(j));
+            }
+        }
+        self.state.update(now, &scan_lines);
+        Ok(())
+    }
+
+    fn is_pressed(&self, row: usize, col: usize) -> bool {
+        self.state.is_pressed(row, col)
+    }
+
+    fn just_pressed(&self, row: usize, col: usize) -> bool {
+        self.state.just_pressed(row, col)
+    }
+
+    fn just_released(&self, row: usize, col: usize) -> bool {
+        self.state.just_released(row, col)
+    }
+
+    fn ghosted(&self) -> bool {
+        self.state.ghosted
+    }
+
+    fn prepare_wake(&mut self) -> Result<bool, Self::Error> {
+        self.write_lines.set_all()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.state.is_idle()
+    }
+
+    fn for_each_event<F>(&self, timestamp: Instant, f: F)
+    where
+        F: FnMut(KeyEvent),
+    {
+        self.state.for_each_event(timestamp, f)
+    }
+}
+
+/// A scan matrix whose lines take turns acting as write lines and read lines.
+///
+/// This is needed for matrices like [`Duplex`](crate::diodes::Duplex), where
+/// the same pins are used as both write lines and read lines in different
+/// phases of the scan. Otherwise, it behaves the same as [`ScanMatrix`].
+pub struct DuplexMatrix<L, D, C, B, const ROWS: usize, const COLS: usize>
+where
+    [(); row_bytes(COLS)]:,
+{
+    lines: L,
+    scan_delay: D,
+    state: MatrixState<C, B, ROWS, COLS>,
+}
+
+impl<L, D, C, B, const ROWS: usize, const COLS: usize> DuplexMatrix<L, D, C, B, ROWS, COLS>
+where
+    C: DiodeConfiguration<ROWS, COLS>,
+    L: DuplexLines<{ C::WRITE_LINES }>,
+    D: FnMut(),
+    B: Debouncer<ROWS, COLS>,
+    [(); row_bytes(COLS)]:,
+{
+    const SAME_LINES: () = assert!(
+        C::WRITE_LINES == C::READ_LINES,
+        "duplex matrix must have the same write lines and read lines"
+    );
+
+    pub fn new(lines: L, scan_delay: D, debouncer: B) -> Self {
+        let () = Self::SAME_LINES;
+        Self {
+            lines,
+            scan_delay,
+            state: MatrixState::new(debouncer),
+        }
+    }
+}
+
+impl<L, D, C, B, const ROWS: usize, const COLS: usize> Scanner<ROWS, COLS>
+    for DuplexMatrix<L, D, C, B, ROWS, COLS>
+where
+    C: DiodeConfiguration<ROWS, COLS>,
+    L: DuplexLines<{ C::WRITE_LINES }>,
+    D: FnMut(),
+    B: Debouncer<ROWS, COLS>,
+    [(); row_bytes(COLS)]:,
+    [(); row_bytes(C::READ_LINES)]:,
+    [(); C::WRITE_LINES]:,
+{
+    type Error = L::Error;
 
-                    self.new_state[row] |= 1 << col;
+    fn poll(&mut self, now: Instant) -> Result<(), Self::Error> {
+        let mut scan_lines = [ScanRow::<{ C::READ_LINES }>::EMPTY; C::WRITE_LINES];
+        for (i, scan_line) in scan_lines.iter_mut().enumerate() {
+            self.lines.set(i)?;
+            (self.scan_delay)();
+            for j in 0..C::READ_LINES {
+                if is_key::<C, ROWS, COLS>(i, j) && self.lines.poll(j)? {
+                    scan_line.set(j, true);
                 }
             }
         }
+        self.state.update(now, &scan_lines);
+        Ok(())
+    }
+
+    fn is_pressed(&self, row: usize, col: usize) -> bool {
+        self.state.is_pressed(row, col)
+    }
+
+    fn just_pressed(&self, row: usize, col: usize) -> bool {
+        self.state.just_pressed(row, col)
+    }
+
+    fn just_released(&self, row: usize, col: usize) -> bool {
+        self.state.just_released(row, col)
+    }
+
+    fn ghosted(&self) -> bool {
+        self.state.ghosted
+    }
+    fn is_idle(&self) -> bool {
+        self.state.is_idle()
+    }
+
+    fn for_each_event<F>(&self, timestamp: Instant, f: F)
+    where
+        F: FnMut(KeyEvent),
+    {
+        self.state.for_each_event(timestamp, f)
+    }
+}
+
+/// A scanner for keyboards without a matrix, where every key is wired to its
+/// own input pin.
+///
+/// The mapping from pins to logical key positions is given by the
+/// configuration `C`, which must have exactly one write line; pin `i` of the
+/// group corresponds to read line `i`. [`RowMajor`](crate::diodes::RowMajor)
+/// assigns the pins to keys in order, and a single-line
+/// [`Lookup`](crate::diodes::Lookup) table can describe any other assignment.
+///
+/// The polarity `P` selects whether a pressed key drives its pin low
+/// ([`ActiveLow`], with pull-up resistors) or high ([`ActiveHigh`], with
+/// pull-down resistors).
+pub struct DirectPins<G, P, C, B, const ROWS: usize, const COLS: usize>
+where
+    [(); row_bytes(COLS)]:,
+{
+    pins: G,
+    _polarity: PhantomData<P>,
+    state: MatrixState<C, B, ROWS, COLS>,
+}
+
+impl<G, P, C, B, const ROWS: usize, const COLS: usize> DirectPins<G, P, C, B, ROWS, COLS>
+where
+    C: DiodeConfiguration<ROWS, COLS>,
+    G: InputGroup<{ C::READ_LINES }>,
+    P: Polarity,
+    B: Debouncer<ROWS, COLS>,
+    [(); row_bytes(COLS)]:,
+{
+    const ONE_WRITE_LINE: () = assert!(
+        C::WRITE_LINES == 1,
+        "direct pins configuration must have a single write line"
+    );
+
+    pub fn new(pins: G, debouncer: B) -> Self {
+        let () = Self::ONE_WRITE_LINE;
+        Self {
+            pins,
+            _polarity: PhantomData,
+            state: MatrixState::new(debouncer),
+        }
+    }
+}
+
+impl<G, P, C, B, const ROWS: usize, const COLS: usize> Scanner<ROWS, COLS>
+    for DirectPins<G, P, C, B, ROWS, COLS>
+where
+    C: DiodeConfiguration<ROWS, COLS>,
+    G: InputGroup<{ C::READ_LINES }>,
+    P: Polarity,
+    B: Debouncer<ROWS, COLS>,
+    [(); row_bytes(COLS)]:,
+    [(); row_bytes(C::READ_LINES)]:,
+    [(); C::WRITE_LINES]:,
+{
+    type Error = G::Error;
+
+    fn poll(&mut self, now: Instant) -> Result<(), Self::Error> {
+        let mut scan_lines = [ScanRow::<{ C::READ_LINES }>::EMPTY; C::WRITE_LINES];
+        for j in 0..C::READ_LINES {
+            if is_key::<C, ROWS, COLS>(0, j) && P::is_active(&self.pins, j)? {
+                scan_lines[0].set(j, true);
+            }
+        }
+        self.state.update(now, &scan_lines);
         Ok(())
     }
 
     fn is_pressed(&self, row: usize, col: usize) -> bool {
-        (self.new_state[row] & (1 << col)) != 0
+        self.state.is_pressed(row, col)
     }
 
     fn just_pressed(&self, row: usize, col: usize) -> bool {
-        ((self.new_state[row] & !self.old_state[row]) & (1 << col)) != 0
+        self.state.just_pressed(row, col)
     }
 
     fn just_released(&self, row: usize, col: usize) -> bool {
-        ((!self.new_state[row] & self.old_state[row]) & (1 << col)) != 0
+        self.state.just_released(row, col)
+    }
+
+    /// Every key has its own pin, so any key press is always visible.
+    fn prepare_wake(&mut self) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+
+    fn is_idle(&self) -> bool {
+        self.state.is_idle()
+    }
+
+    fn for_each_event<F>(&self, timestamp: Instant, f: F)
+    where
+        F: FnMut(KeyEvent),
+    {
+        self.state.for_each_event(timestamp, f)
+    }
+}
+
+/// The level of an input pin while its key is pressed.
+pub trait Polarity {
+    /// Whether the pin at `index` is at its active level.
+    fn is_active<G, const LEN: usize>(pins: &G, index: usize) -> Result<bool, G::Error>
+    where
+        G: InputGroup<LEN>;
+}
+
+/// Pins are pulled high, and driven low while their key is pressed.
+pub struct ActiveLow {
+    _private: (),
+}
+
+impl Polarity for ActiveLow {
+    fn is_active<G, const LEN: usize>(pins: &G, index: usize) -> Result<bool, G::Error>
+    where
+        G: InputGroup<LEN>,
+    {
+        pins.is_low(index)
+    }
+}
+
+/// Pins are pulled low, and driven high while their key is pressed.
+pub struct ActiveHigh {
+    _private: (),
+}
+
+impl Polarity for ActiveHigh {
+    fn is_active<G, const LEN: usize>(pins: &G, index: usize) -> Result<bool, G::Error>
+    where
+        G: InputGroup<LEN>,
+    {
+        pins.is_high(index)
+    }
+}
+
+/// Whether there is a key at the given matrix position.
+fn is_key<C, const ROWS: usize, const COLS: usize>(write_index: usize, read_index: usize) -> bool
+where
+    C: DiodeConfiguration<ROWS, COLS>,
+{
+    C::key_position(ScanPosition {
+        write_index,
+        read_index,
+    })
+    .is_some()
+}
+
+/// Debounced key state of a matrix, updated from the electrical state of the
+/// matrix read during each scan.
+pub(crate) struct MatrixState<C, B, const ROWS: usize, const COLS: usize>
+where
+    [(); row_bytes(COLS)]:,
+{
+    debouncer: B,
+    _diodes: PhantomData<C>,
+    old_state: [ScanRow<COLS>; ROWS],
+    new_state: [ScanRow<COLS>; ROWS],
+    raw_state: [ScanRow<COLS>; ROWS],
+    pub(crate) ghosted: bool,
+}
+
+impl<C, B, const ROWS: usize, const COLS: usize> MatrixState<C, B, ROWS, COLS>
+where
+    C: DiodeConfiguration<ROWS, COLS>,
+    B: Debouncer<ROWS, COLS>,
+    [(); row_bytes(COLS)]:,
+{
+    pub(crate) fn new(debouncer: B) -> Self {
+        Self {
+            debouncer,
+            _diodes: PhantomData,
+            old_state: [ScanRow::EMPTY; ROWS],
+            new_state: [ScanRow::EMPTY; ROWS],
+            raw_state: [ScanRow::EMPTY; ROWS],
+            ghosted: false,
+        }
+    }
+
+    /// Updates the key state, given the read lines that are connected to each
+    /// write line, as read at time `now`.
+    pub(crate) fn update(
+        &mut self,
+        now: Instant,
+        scan_lines: &[ScanRow<{ C::READ_LINES }>; C::WRITE_LINES],
+    ) where
+        [(); row_bytes(C::READ_LINES)]:,
+        [(); C::WRITE_LINES]:,
+    {
+        // Positions on the corners of any rectangles in the matrix, which
+        // cannot be trusted.
+        let mut ghosts = [ScanRow::<{ C::READ_LINES }>::EMPTY; C::WRITE_LINES];
+        self.ghosted = false;
+        if C::CAN_GHOST {
+            for i in 0..C::WRITE_LINES {
+                for k in (i + 1)..C::WRITE_LINES {
+                    let shared = scan_lines[i] & scan_lines[k];
+                    if shared.count() > 1 {
+                        ghosts[i] |= shared;
+                        ghosts[k] |= shared;
+                        self.ghosted = true;
+                    }
+                }
+            }
+        }
+
+        let mut raw_state = [ScanRow::<COLS>::EMPTY; ROWS];
+        for i in 0..C::WRITE_LINES {
+            for j in 0..C::READ_LINES {
+                let Some(KeyPosition { row, col }) = C::key_position(ScanPosition {
+                    write_index: i,
+                    read_index: j,
+                }) else {
+                    continue;
+                };
+                let pressed = if ghosts[i].get(j) {
+                    self.is_pressed(row, col)
+                } else {
+                    scan_lines[i].get(j)
+                };
+                raw_state[row].set(col, pressed);
+            }
+        }
+
+        self.old_state = self.new_state;
+        self.debouncer
+            .debounce(now, &raw_state, &mut self.new_state);
+        self.raw_state = raw_state;
+    }
+
+    /// Whether the debounced state has caught up with the last raw reading.
+    pub(crate) fn is_idle(&self) -> bool {
+        self.raw_state == self.new_state
+    }
+
+    pub(crate) fn is_pressed(&self, row: usize, col: usize) -> bool {
+        self.new_state[row].get(col)
+    }
+
+    pub(crate) fn just_pressed(&self, row: usize, col: usize) -> bool {
+        (self.new_state[row] & !self.old_state[row]).get(col)
+    }
+
+    pub(crate) fn just_released(&self, row: usize, col: usize) -> bool {
+        (!self.new_state[row] & self.old_state[row]).get(col)
+    }
+
+    /// Emits the key events of the last update, in scan order: by write line,
+    /// then by read line.
+    pub(crate) fn for_each_event<F>(&self, timestamp: Instant, mut f: F)
+    where
+        F: FnMut(KeyEvent),
+    {
+        for write_index in 0..C::WRITE_LINES {
+            for read_index in 0..C::READ_LINES {
+                let Some(KeyPosition { row, col }) = C::key_position(ScanPosition {
+                    write_index,
+                    read_index,
+                }) else {
+                    continue;
+                };
+                let action = if self.just_pressed(row, col) {
+                    KeyAction::Pressed
+                } else if self.just_released(row, col) {
+                    KeyAction::Released
+                } else {
+                    continue;
+                };
+                f(KeyEvent {
+                    row,
+                    col,
+                    action,
+                    timestamp,
+                });
+            }
+        }
     }
 }
 
 pub trait ReadLines<const LEN: usize> {
     type Error;
 
+    /// Latches the state of the read lines, after a write line has been
+    /// selected and the scan delay has passed.
+    ///
+    /// The scanner calls this before polling the lines for each write line.
+    /// Lines that can only be sampled all at once, like a shift register, are
+    /// refreshed here, and polling them returns the state from the last latch.
+    /// By default, this does nothing.
+    fn latch(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
     fn poll(&mut self, index: usize) -> Result<bool, Self::Error>;
+
+    /// Polls all of the read lines at once, returning the set of lines that
+    /// are connected to the selected write line.
+    ///
+    /// By default, this polls each line individually. Implementations that
+    /// can sample several lines at once override this, so that each sample is
+    /// only taken once per write line.
+    fn poll_all(&mut self) -> Result<ScanRow<LEN>, Self::Error>
+    where
+        [(); row_bytes(LEN)]:,
+    {
+        let mut lines = ScanRow::EMPTY;
+        for i in 0..LEN {
+            lines.set(i, self.poll(i)?);
+        }
+        Ok(lines)
+    }
 }
 
 pub trait WriteLines<const LEN: usize> {
     type Error;
 
     fn set(&mut self, index: usize) -> Result<(), Self::Error>;
+
+    /// Drives all of the write lines active at once.
+    ///
+    /// Returns false if the lines do not support this, which is the default.
+    fn set_all(&mut self) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+}
+
+/// Lines that can act as both write lines and read lines, switching roles
+/// between phases of the scan.
+pub trait DuplexLines<const LEN: usize> {
+    type Error;
+
+    /// Selects the line at `index` as the write line, and releases the rest of
+    /// the lines so they can be read.
+    fn set(&mut self, index: usize) -> Result<(), Self::Error>;
+
+    /// Reads one of the released lines.
+    fn poll(&mut self, index: usize) -> Result<bool, Self::Error>;
 }
 
 /// A WriteLines or ReadLines, made from an OutputGroup or InputGroup, where
@@ -124,6 +773,10 @@ pub trait WriteLines<const LEN: usize> {
 /// When used as [`ReadLines`], it is assumed that the lines are pulled high by
 /// default and connected to a write line when the corresponding key is
 /// pressed, therefore being driven low when that write line is selected.
+///
+/// When used as [`DuplexLines`], the pins must be open-drain outputs that can
+/// also be read as inputs. Setting a pin high releases it, so it can be pulled
+/// low by the selected write line.
 pub struct Direct<Group>(pub Group);
 
 impl<Group, const LEN: usize> ReadLines<LEN> for Direct<Group>
@@ -135,6 +788,13 @@ where
     fn poll(&mut self, index: usize) -> Result<bool, Self::Error> {
         self.0.is_low(index)
     }
+
+    fn poll_all(&mut self) -> Result<ScanRow<LEN>, Self::Error>
+    where
+        [(); row_bytes(LEN)]:,
+    {
+        self.0.read_low()
+    }
 }
 
 impl<Group, const LEN: usize> WriteLines<LEN> for Direct<Group>
@@ -150,4 +810,444 @@ where
         self.0.set_low(index)?;
         Ok(())
     }
+
+    fn set_all(&mut self) -> Result<bool, Self::Error> {
+        for i in 0..LEN {
+            self.0.set_low(i)?;
+        }
+        Ok(true)
+    }
+}
+
+impl<Group, const LEN: usize> DuplexLines<LEN> for Direct<Group>
+where
+    Group: OutputGroup<LEN> + InputGroup<LEN, Error = <Group as OutputGroup<LEN>>::Error>,
+{
+    type Error = <Group as OutputGroup<LEN>>::Error;
+
+    fn set(&mut self, index: usize) -> Result<(), Self::Error> {
+        WriteLines::set(self, index)
+    }
+
+    fn poll(&mut self, index: usize) -> Result<bool, Self::Error> {
+        ReadLines::poll(self, index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::debounce::{DeferredPerKey, NoDebounce};
+    use crate::diodes::{
+        ColToRow, Duplex, Folded, KeyPosition, Lookup, LookupTable, NoDiodes, RowMajor,
+    };
+    use crate::time::Duration;
+
+    use core::cell::{Cell, RefCell};
+    use core::convert::Infallible;
+    use embedded_hal_mock::pin::{Mock, State, Transaction};
+    use std::rc::Rc;
+    use std::vec::Vec;
+
+    /// Write and read lines of a simulated switch matrix.
+    #[derive(Clone, Default)]
+    struct FakeLines {
+        selected: Rc<Cell<usize>>,
+        closed: Rc<RefCell<Vec<ScanPosition>>>,
+    }
+
+    impl FakeLines {
+        fn close(&self, write_index: usize, read_index: usize) {
+            self.closed.borrow_mut().push(ScanPosition {
+                write_index,
+                read_index,
+            });
+        }
+    }
+
+    impl<const LEN: usize> WriteLines<LEN> for FakeLines {
+        type Error = Infallible;
+
+        fn set(&mut self, index: usize) -> Result<(), Self::Error> {
+            self.selected.set(index);
+            Ok(())
+        }
+    }
+
+    impl<const LEN: usize> ReadLines<LEN> for FakeLines {
+        type Error = Infallible;
+
+        fn poll(&mut self, index: usize) -> Result<bool, Self::Error> {
+            let selected = self.selected.get();
+            Ok(self
+                .closed
+                .borrow()
+                .iter()
+                .any(|pos| pos.write_index == selected && pos.read_index == index))
+        }
+    }
+
+    impl<const LEN: usize> DuplexLines<LEN> for FakeLines {
+        type Error = Infallible;
+
+        fn set(&mut self, index: usize) -> Result<(), Self::Error> {
+            WriteLines::<LEN>::set(self, index)
+        }
+
+        fn poll(&mut self, index: usize) -> Result<bool, Self::Error> {
+            ReadLines::<LEN>::poll(self, index)
+        }
+    }
+
+    /// Builds mock write and read lines for a 2x2 matrix, where each frame
+    /// lists the keys that appear pressed on each scan, indexed by
+    /// `[write_index][read_index]`.
+    fn mock_lines(frames: &[[[bool; 2]; 2]]) -> ([Mock; 2], [Mock; 2]) {
+        let write_pins = [0, 1].map(|pin| {
+            let mut transactions = Vec::new();
+            for _ in frames {
+                for i in 0..2 {
+                    transactions.push(Transaction::set(State::High));
+                    if i == pin {
+                        transactions.push(Transaction::set(State::Low));
+                    }
+                }
+            }
+            Mock::new(&transactions)
+        });
+        let read_pins = [0, 1].map(|pin| {
+            let mut transactions = Vec::new();
+            for frame in frames {
+                for line in frame {
+                    transactions.push(Transaction::get(if line[pin] {
+                        State::Low
+                    } else {
+                        State::High
+                    }));
+                }
+            }
+            Mock::new(&transactions)
+        });
+        (write_pins, read_pins)
+    }
+
+    fn pressed<S: Scanner<2, 2>>(scanner: &S) -> [[bool; 2]; 2] {
+        [0, 1].map(|row| [0, 1].map(|col| scanner.is_pressed(row, col)))
+    }
+
+    #[test]
+    fn ghosting_blocks_rectangle() {
+        let frames = [
+            // Two keys on the same write line.
+            [[true, true], [false, false]],
+            // A third key is pressed, and the fourth corner appears as well.
+            [[true, true], [true, true]],
+            // The third key is released.
+            [[true, true], [false, false]],
+        ];
+        let (mut write_pins, mut read_pins) = mock_lines(&frames);
+        let mut matrix: ScanMatrix<_, _, _, NoDiodes, _, 2, 2> = ScanMatrix::new(
+            Direct(write_pins.clone()),
+            Direct(read_pins.clone()),
+            || {},
+            NoDebounce,
+        );
+
+        matrix.poll(Instant::from_ticks(0)).unwrap();
+        assert!(!matrix.ghosted());
+        assert_eq!(pressed(&matrix), [[true, true], [false, false]]);
+
+        matrix.poll(Instant::from_ticks(0)).unwrap();
+        assert!(matrix.ghosted());
+        assert_eq!(pressed(&matrix), [[true, true], [false, false]]);
+        assert!(!matrix.just_pressed(1, 0));
+        assert!(!matrix.just_pressed(1, 1));
+
+        matrix.poll(Instant::from_ticks(0)).unwrap();
+        assert!(!matrix.ghosted());
+        assert_eq!(pressed(&matrix), [[true, true], [false, false]]);
+
+        for pin in write_pins.iter_mut().chain(&mut read_pins) {
+            pin.done();
+        }
+    }
+
+    #[test]
+    fn diodes_do_not_ghost() {
+        let frames = [[[true, true], [true, true]]];
+        let (mut write_pins, mut read_pins) = mock_lines(&frames);
+        let mut matrix: ScanMatrix<_, _, _, ColToRow, _, 2, 2> = ScanMatrix::new(
+            Direct(write_pins.clone()),
+            Direct(read_pins.clone()),
+            || {},
+            NoDebounce,
+        );
+
+        matrix.poll(Instant::from_ticks(0)).unwrap();
+        assert!(!matrix.ghosted());
+        assert_eq!(pressed(&matrix), [[true, true], [true, true]]);
+
+        for pin in write_pins.iter_mut().chain(&mut read_pins) {
+            pin.done();
+        }
+    }
+
+    #[test]
+    fn prepare_wake_sets_all_write_lines() {
+        let mut write_pins = [0, 1].map(|_| Mock::new(&[Transaction::set(State::Low)]));
+        let mut read_pins = [0, 1].map(|_| Mock::new(&[]));
+        let mut matrix: ScanMatrix<_, _, _, ColToRow, _, 2, 2> = ScanMatrix::new(
+            Direct(write_pins.clone()),
+            Direct(read_pins.clone()),
+            || {},
+            NoDebounce,
+        );
+
+        assert!(matrix.prepare_wake().unwrap());
+
+        for pin in write_pins.iter_mut().chain(&mut read_pins) {
+            pin.done();
+        }
+    }
+
+    #[test]
+    fn not_idle_while_debouncing() {
+        let lines = FakeLines::default();
+        let mut matrix: ScanMatrix<_, _, _, ColToRow, _, 2, 2> = ScanMatrix::new(
+            lines.clone(),
+            lines.clone(),
+            || {},
+            DeferredPerKey::new(Duration::millis(5)),
+        );
+        matrix.poll(Instant::from_ticks(0)).unwrap();
+        assert!(matrix.is_idle());
+
+        lines.close(1, 0);
+        matrix.poll(Instant::from_ticks(1)).unwrap();
+        assert!(!matrix.is_pressed(1, 0));
+        assert!(!matrix.is_idle());
+
+        matrix.poll(Instant::from_ticks(6)).unwrap();
+        assert!(matrix.is_pressed(1, 0));
+        assert!(matrix.is_idle());
+    }
+
+    #[test]
+    fn wide_8x40() {
+        let lines = FakeLines::default();
+        let mut matrix: ScanMatrix<_, _, _, ColToRow, _, 8, 40> =
+            ScanMatrix::new(lines.clone(), lines.clone(), || {}, NoDebounce);
+
+        let keys = [(0, 0), (0, 31), (0, 32), (3, 17), (7, 39)];
+        for &(row, col) in &keys {
+            lines.close(row, col);
+        }
+        matrix.poll(Instant::from_ticks(0)).unwrap();
+
+        for row in 0..8 {
+            for col in 0..40 {
+                let expected = keys.contains(&(row, col));
+                assert_eq!(matrix.is_pressed(row, col), expected, "({row}, {col})");
+                assert_eq!(matrix.just_pressed(row, col), expected, "({row}, {col})");
+            }
+        }
+    }
+
+    #[test]
+    fn wide_1x64() {
+        let lines = FakeLines::default();
+        let mut matrix: ScanMatrix<_, _, _, ColToRow, _, 1, 64> =
+            ScanMatrix::new(lines.clone(), lines.clone(), || {}, NoDebounce);
+
+        lines.close(0, 63);
+        lines.close(0, 40);
+        matrix.poll(Instant::from_ticks(0)).unwrap();
+        lines.closed.borrow_mut().clear();
+        lines.close(0, 63);
+        matrix.poll(Instant::from_ticks(0)).unwrap();
+
+        for col in 0..64 {
+            assert_eq!(matrix.is_pressed(0, col), col == 63, "{col}");
+            assert!(!matrix.just_pressed(0, col), "{col}");
+            assert_eq!(matrix.just_released(0, col), col == 40, "{col}");
+        }
+    }
+
+    #[test]
+    fn scan_row_not_clears_padding() {
+        let row = ScanRow::<12>::EMPTY;
+        assert_eq!((!row).count(), 12);
+        assert!(!!row == row);
+    }
+
+    #[test]
+    fn duplex_6x24() {
+        let lines = FakeLines::default();
+        let mut matrix: DuplexMatrix<_, _, Duplex, _, 6, 24> =
+            DuplexMatrix::new(lines.clone(), || {}, NoDebounce);
+
+        // Row pins are lines 0..6, column pins are lines 6..18.
+        // Row 0 driving column pin 0:
+        lines.close(0, 6);
+        // Column pin 11 driving row 5:
+        lines.close(17, 5);
+        // Column pin 3 driving row 2:
+        lines.close(9, 2);
+        matrix.poll(Instant::from_ticks(0)).unwrap();
+
+        let keys = [(0, 0), (5, 23), (2, 7)];
+        for row in 0..6 {
+            for col in 0..24 {
+                let expected = keys.contains(&(row, col));
+                assert_eq!(matrix.is_pressed(row, col), expected, "({row}, {col})");
+            }
+        }
+    }
+
+    #[test]
+    fn folded_4x12() {
+        let lines = FakeLines::default();
+        // Folded into an 8x6 electrical matrix.
+        let mut matrix: ScanMatrix<_, _, _, Folded<ColToRow, 2>, _, 4, 12> =
+            ScanMatrix::new(lines.clone(), lines.clone(), || {}, NoDebounce);
+
+        lines.close(1, 0);
+        lines.close(6, 5);
+        matrix.poll(Instant::from_ticks(0)).unwrap();
+
+        let keys = [(0, 1), (3, 10)];
+        for row in 0..4 {
+            for col in 0..12 {
+                let expected = keys.contains(&(row, col));
+                assert_eq!(matrix.is_pressed(row, col), expected, "({row}, {col})");
+            }
+        }
+    }
+
+    struct IrregularTable;
+
+    const fn key(row: usize, col: usize) -> Option<KeyPosition> {
+        Some(KeyPosition::new(row, col))
+    }
+
+    impl LookupTable<2, 3> for IrregularTable {
+        const CAN_GHOST: bool = false;
+        const TABLE: &'static [&'static [Option<KeyPosition>]] = &[
+            &[key(0, 0), key(1, 2), None],
+            &[key(0, 2), key(1, 0), key(0, 1)],
+            &[None, None, key(1, 1)],
+        ];
+    }
+
+    #[test]
+    fn lookup_2x3() {
+        let lines = FakeLines::default();
+        let mut matrix: ScanMatrix<_, _, _, Lookup<IrregularTable>, _, 2, 3> =
+            ScanMatrix::new(lines.clone(), lines.clone(), || {}, NoDebounce);
+
+        // The unused intersection (2, 0) is ignored.
+        lines.close(0, 1);
+        lines.close(2, 0);
+        lines.close(2, 2);
+        matrix.poll(Instant::from_ticks(0)).unwrap();
+
+        let keys = [(1, 2), (1, 1)];
+        for row in 0..2 {
+            for col in 0..3 {
+                let expected = keys.contains(&(row, col));
+                assert_eq!(matrix.is_pressed(row, col), expected, "({row}, {col})");
+            }
+        }
+    }
+
+    #[test]
+    fn events_in_scan_order() {
+        let lines = FakeLines::default();
+        let mut matrix: ScanMatrix<_, _, _, Lookup<IrregularTable>, _, 2, 3> =
+            ScanMatrix::new(lines.clone(), lines.clone(), || {}, NoDebounce);
+        let events = |matrix: &ScanMatrix<_, _, _, _, _, 2, 3>, ticks| {
+            let mut events = Vec::new();
+            matrix.for_each_event(Instant::from_ticks(ticks), |event| events.push(event));
+            events
+        };
+        let event = |row, col, action, ticks| KeyEvent {
+            row,
+            col,
+            action,
+            timestamp: Instant::from_ticks(ticks),
+        };
+
+        lines.close(0, 1);
+        lines.close(1, 0);
+        lines.close(2, 2);
+        matrix.poll(Instant::from_ticks(0)).unwrap();
+        assert!(
+            events(&matrix, 5)
+                == [
+                    event(1, 2, KeyAction::Pressed, 5),
+                    event(0, 2, KeyAction::Pressed, 5),
+                    event(1, 1, KeyAction::Pressed, 5),
+                ]
+        );
+
+        lines.closed.borrow_mut().clear();
+        lines.close(1, 0);
+        lines.close(0, 0);
+        matrix.poll(Instant::from_ticks(0)).unwrap();
+        assert!(
+            events(&matrix, 6)
+                == [
+                    event(0, 0, KeyAction::Pressed, 6),
+                    event(1, 2, KeyAction::Released, 6),
+                    event(1, 1, KeyAction::Released, 6),
+                ]
+        );
+    }
+
+    #[test]
+    fn direct_pins_active_low() {
+        let levels = [State::High, State::Low];
+        let mut pins = levels.map(|level| Mock::new(&[Transaction::get(level)]));
+        let mut scanner: DirectPins<_, ActiveLow, RowMajor, _, 2, 1> =
+            DirectPins::new(pins.clone(), NoDebounce);
+        scanner.poll(Instant::from_ticks(0)).unwrap();
+        for pin in &mut pins {
+            pin.done();
+        }
+
+        // Pins are assigned to keys in row-major order.
+        assert!(!scanner.is_pressed(0, 0));
+        assert!(scanner.is_pressed(1, 0));
+    }
+
+    struct ReversedPins;
+
+    impl LookupTable<1, 3> for ReversedPins {
+        const CAN_GHOST: bool = false;
+        const TABLE: &'static [&'static [Option<KeyPosition>]] =
+            &[&[key(0, 2), None, key(0, 1), key(0, 0)]];
+    }
+
+    #[test]
+    fn direct_pins_active_high() {
+        // The unused pin is never read.
+        let mut pins = [
+            Mock::new(&[Transaction::get(State::High)]),
+            Mock::new(&[]),
+            Mock::new(&[Transaction::get(State::Low)]),
+            Mock::new(&[Transaction::get(State::High)]),
+        ];
+        let mut scanner: DirectPins<_, ActiveHigh, Lookup<ReversedPins>, _, 1, 3> =
+            DirectPins::new(pins.clone(), NoDebounce);
+        scanner.poll(Instant::from_ticks(0)).unwrap();
+        for pin in &mut pins {
+            pin.done();
+        }
+
+        assert!(scanner.is_pressed(0, 0));
+        assert!(!scanner.is_pressed(0, 1));
+        assert!(scanner.is_pressed(0, 2));
+    }
 }
@@ -0,0 +1,227 @@
+//! Matrix lines behind shift registers.
+//!
+//! Boards with few spare pins can drive their write lines through a chain of
+//! serial-in, parallel-out shift registers (like the 74HC595), and sample their
+//! read lines through a chain of parallel-in, serial-out shift registers (like
+//! the 74HC165). Either chain only needs three pins, no matter how many lines
+//! it has.
+
+use crate::scanner::{row_bytes, ReadLines, ScanRow, WriteLines};
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+/// [`WriteLines`] driven through a chain of serial-in, parallel-out shift
+/// registers, like the 74HC595.
+///
+/// Line 0 is output A of the register connected to the microcontroller, line 7
+/// is its output H, line 8 is output A of the next register in the chain, and
+/// so on.
+///
+/// Like [`Direct`](crate::scanner::Direct), the selected line is driven low
+/// and the rest of the lines are set high. The clock and latch pins are
+/// expected to idle low.
+pub struct ShiftOut<Data, Clock, Latch> {
+    data: Data,
+    clock: Clock,
+    latch: Latch,
+}
+
+impl<Data, Clock, Latch> ShiftOut<Data, Clock, Latch>
+where
+    Data: OutputPin,
+    Clock: OutputPin<Error = Data::Error>,
+    Latch: OutputPin<Error = Data::Error>,
+{
+    /// Creates a chain from its serial data pin (SER), its shift clock pin
+    /// (SRCLK), and its storage register clock pin (RCLK).
+    pub fn new(data: Data, clock: Clock, latch: Latch) -> Self {
+        Self { data, clock, latch }
+    }
+}
+
+impl<Data, Clock, Latch, const LEN: usize> WriteLines<LEN> for ShiftOut<Data, Clock, Latch>
+where
+    Data: OutputPin,
+    Clock: OutputPin<Error = Data::Error>,
+    Latch: OutputPin<Error = Data::Error>,
+{
+    type Error = Data::Error;
+
+    fn set(&mut self, index: usize) -> Result<(), Self::Error> {
+        // The first bit shifted in ends up furthest down the chain.
+        for i in (0..LEN).rev() {
+            if i == index {
+                self.data.set_low()?;
+            } else {
+                self.data.set_high()?;
+            }
+            self.clock.set_high()?;
+            self.clock.set_low()?;
+        }
+        self.latch.set_high()?;
+        self.latch.set_low()?;
+        Ok(())
+    }
+
+    fn set_all(&mut self) -> Result<bool, Self::Error> {
+        self.data.set_low()?;
+        for _ in 0..LEN {
+            self.clock.set_high()?;
+            self.clock.set_low()?;
+        }
+        self.latch.set_high()?;
+        self.latch.set_low()?;
+        Ok(true)
+    }
+}
+
+/// [`ReadLines`] sampled through a chain of parallel-in, serial-out shift
+/// registers, like the 74HC165.
+///
+/// Line 0 is input H of the register connected to the microcontroller, line 7
+/// is its input A, line 8 is input H of the next register in the chain, and so
+/// on.
+///
+/// Like [`Direct`](crate::scanner::Direct), the lines are assumed to be pulled
+/// high, and driven low when connected to the selected write line. The clock
+/// pin is expected to idle low, and the load pin to idle high.
+///
+/// All of the lines are sampled at once, when they are
+/// [latched](ReadLines::latch) by the scanner after selecting a write line.
+pub struct ShiftIn<Data, Clock, Load, const LEN: usize>
+where
+    [(); row_bytes(LEN)]:,
+{
+    data: Data,
+    clock: Clock,
+    load: Load,
+    sampled: ScanRow<LEN>,
+}
+
+impl<Data, Clock, Load, const LEN: usize> ShiftIn<Data, Clock, Load, LEN>
+where
+    Data: InputPin,
+    Clock: OutputPin<Error = Data::Error>,
+    Load: OutputPin<Error = Data::Error>,
+    [(); row_bytes(LEN)]:,
+{
+    /// Creates a chain from its serial output pin (QH), its clock pin (CLK),
+    /// and its shift/load pin (SH/LD).
+    pub fn new(data: Data, clock: Clock, load: Load) -> Self {
+        Self {
+            data,
+            clock,
+            load,
+            sampled: ScanRow::EMPTY,
+        }
+    }
+}
+
+impl<Data, Clock, Load, const LEN: usize> ReadLines<LEN> for ShiftIn<Data, Clock, Load, LEN>
+where
+    Data: InputPin,
+    Clock: OutputPin<Error = Data::Error>,
+    Load: OutputPin<Error = Data::Error>,
+    [(); row_bytes(LEN)]:,
+{
+    type Error = Data::Error;
+
+    /// Latches the state of all the lines and shifts it in.
+    fn latch(&mut self) -> Result<(), Self::Error> {
+        self.load.set_low()?;
+        self.load.set_high()?;
+        for i in 0..LEN {
+            if i != 0 {
+                self.clock.set_high()?;
+                self.clock.set_low()?;
+            }
+            self.sampled.set(i, self.data.is_low()?);
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self, index: usize) -> Result<bool, Self::Error> {
+        Ok(self.sampled.get(index))
+    }
+
+    fn poll_all(&mut self) -> Result<ScanRow<LEN>, Self::Error> {
+        Ok(self.sampled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    use embedded_hal_mock::pin::{Mock, State, Transaction};
+    use std::vec::Vec;
+
+    fn pulses(count: usize) -> Vec<Transaction> {
+        (0..count)
+            .flat_map(|_| [Transaction::set(State::High), Transaction::set(State::Low)])
+            .collect()
+    }
+
+    #[test]
+    fn shift_out_bit_order() {
+        // Line 2 of 10 is selected, so the low bit is shifted in third to last.
+        let bits = [1, 1, 1, 1, 1, 1, 1, 0, 1, 1];
+        let mut data = Mock::new(
+            &bits.map(|bit| Transaction::set(if bit == 0 { State::Low } else { State::High })),
+        );
+        let mut clock = Mock::new(&pulses(10));
+        let mut latch = Mock::new(&pulses(1));
+
+        let mut lines = ShiftOut::new(data.clone(), clock.clone(), latch.clone());
+        WriteLines::<10>::set(&mut lines, 2).unwrap();
+
+        data.done();
+        clock.done();
+        latch.done();
+    }
+
+    #[test]
+    fn shift_in_bit_order() {
+        let first = [
+            State::Low,
+            State::High,
+            State::High,
+            State::Low,
+            State::High,
+            State::High,
+            State::High,
+            State::High,
+            State::High,
+            State::Low,
+        ];
+        let second = [State::High; 10];
+        let mut data = Mock::new(
+            &first
+                .iter()
+                .chain(&second)
+                .map(|&state| Transaction::get(state))
+                .collect::<Vec<_>>(),
+        );
+        let mut clock = Mock::new(&pulses(18));
+        let mut load =
+            Mock::new(&[State::Low, State::High, State::Low, State::High].map(Transaction::set));
+
+        let mut lines: ShiftIn<_, _, _, 10> =
+            ShiftIn::new(data.clone(), clock.clone(), load.clone());
+        // All of the lines are sampled when they are latched.
+        lines.latch().unwrap();
+        assert!(lines.poll(0).unwrap());
+        assert!(!lines.poll(1).unwrap());
+        assert!(lines.poll(3).unwrap());
+        assert!(lines.poll(9).unwrap());
+        assert!(lines.poll(0).unwrap());
+        lines.latch().unwrap();
+        assert!(!lines.poll(0).unwrap());
+        assert!(!lines.poll(9).unwrap());
+
+        data.done();
+        clock.done();
+        load.done();
+    }
+}
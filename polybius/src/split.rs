@@ -0,0 +1,714 @@
+//! Split keyboards, with two halves connected by a serial link.
+//!
+//! The primary half is the one connected to the host; it runs the [`System`]
+//! as usual, and sees the keys of the secondary half through a [`Remote`]
+//! scanner, which can be merged with its own scanner using a
+//! [`Composite`](crate::composite::Composite). Its backlight can be wrapped in
+//! [`Forward`] to apply the same level to the secondary half.
+//!
+//! The secondary half runs a [`Secondary`] instead of a `System`, which sends
+//! the state of its keys to the primary half, and applies the backlight levels
+//! forwarded by it. Which half is which can be decided at startup with
+//! [`Role::detect`], so that either half can be connected to the host.
+//!
+//! The host's LED state (Caps Lock and so on) is not forwarded, because the
+//! [`Uplink`](crate::uplink::Uplink) doesn't report it: the USB uplink discards
+//! the LED output reports, and there is no LED interface to apply them to on
+//! either half.
+//!
+//! # Protocol
+//!
+//! Messages are sent in frames of the form:
+//!
+//! ```text
+//! SYNC kind length payload[length] checksum
+//! ```
+//!
+//! where the checksum is the CRC-8 (polynomial `0x07`) of the kind, length and
+//! payload bytes. Frames with an invalid checksum are dropped, and the receiver
+//! looks for the next `SYNC` byte.
+//!
+//! [`System`]: crate::system::System
+
+use embedded_hal::digital::v2::InputPin;
+use embedded_hal::serial;
+
+use crate::backlight::Backlight;
+use crate::scanner::Scanner;
+use crate::time::{has_elapsed, Clock, Duration, Instant};
+
+/// The largest payload of a frame, which limits the number of keys on the
+/// secondary half to `8 * MAX_PAYLOAD`.
+pub const MAX_PAYLOAD: usize = 32;
+
+/// Marks the start of a frame.
+const SYNC: u8 = 0xa5;
+
+const KIND_MATRIX: u8 = 0x01;
+const KIND_BACKLIGHT: u8 = 0x02;
+
+/// Time after which the secondary half sends the state of its keys again,
+/// even if nothing changed, so that the primary half can recover from dropped
+/// frames or starting up later.
+const RESEND_INTERVAL: Duration = Duration::millis(1000);
+
+/// Time after which the primary half assumes that the link to the secondary
+/// half is lost, and releases all of its keys, if no valid matrix message was
+/// received. This spans a few resend intervals.
+const LINK_TIMEOUT: Duration = Duration::millis(3000);
+
+/// The role of a half of a split keyboard.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// The half connected to the host, which runs the [`System`] with a
+    /// [`Remote`] scanner.
+    ///
+    /// [`System`]: crate::system::System
+    Primary,
+    /// The half that is only connected to the other half, which runs a
+    /// [`Secondary`].
+    Secondary,
+}
+
+impl Role {
+    /// Selects the role of this half by whether it is powered by USB, as
+    /// reported by the `usb_power` pin (e.g. a VBUS sense pin).
+    pub fn detect<P>(usb_power: &P) -> Result<Self, P::Error>
+    where
+        P: InputPin,
+    {
+        if usb_power.is_high()? {
+            Ok(Role::Primary)
+        } else {
+            Ok(Role::Secondary)
+        }
+    }
+}
+
+/// A message sent between the halves of a split keyboard.
+pub enum Message<'a> {
+    /// The state of all keys of the secondary half, packed in row-major order,
+    /// least significant bit first.
+    Matrix(&'a [u8]),
+    /// The backlight level of the primary half.
+    Backlight(u8),
+}
+
+impl<'a> Message<'a> {
+    fn kind(&self) -> u8 {
+        match self {
+            Message::Matrix(_) => KIND_MATRIX,
+            Message::Backlight(_) => KIND_BACKLIGHT,
+        }
+    }
+
+    fn payload(&self) -> &[u8] {
+        match self {
+            Message::Matrix(bits) => bits,
+            Message::Backlight(level) => core::slice::from_ref(level),
+        }
+    }
+}
+
+fn crc8(crc: u8, byte: u8) -> u8 {
+    let mut crc = crc ^ byte;
+    for _ in 0..8 {
+        crc = if crc & 0x80 != 0 {
+            (crc << 1) ^ 0x07
+        } else {
+            crc << 1
+        };
+    }
+    crc
+}
+
+/// Writes a message to the serial link, blocking until it is fully written.
+///
+/// # Panics
+///
+/// Panics if the payload of the message is larger than [`MAX_PAYLOAD`].
+pub fn send<W>(tx: &mut W, message: &Message) -> Result<(), W::Error>
+where
+    W: serial::Write<u8>,
+{
+    let payload = message.payload();
+    assert!(payload.len() <= MAX_PAYLOAD, "payload too large");
+
+    let header = [message.kind(), payload.len() as u8];
+    let checksum = header.iter().chain(payload).fold(0, |crc, &b| crc8(crc, b));
+    nb::block!(tx.write(SYNC))?;
+    for &byte in header.iter().chain(payload) {
+        nb::block!(tx.write(byte))?;
+    }
+    nb::block!(tx.write(checksum))?;
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+enum DecodeState {
+    Sync,
+    Kind,
+    Length,
+    Payload(usize),
+    Checksum,
+}
+
+/// Reassembles frames received from the serial link.
+pub struct Decoder {
+    state: DecodeState,
+    kind: u8,
+    len: usize,
+    crc: u8,
+    buf: [u8; MAX_PAYLOAD],
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Self {
+            state: DecodeState::Sync,
+            kind: 0,
+            len: 0,
+            crc: 0,
+            buf: [0; MAX_PAYLOAD],
+        }
+    }
+
+    /// Handles the next received byte, returning a message if it completes a
+    /// valid frame.
+    ///
+    /// Frames with an invalid checksum or an unknown kind are dropped.
+    pub fn push(&mut self, byte: u8) -> Option<Message<'_>> {
+        match self.state {
+            DecodeState::Sync => {
+                if byte == SYNC {
+                    self.crc = 0;
+                    self.state = DecodeState::Kind;
+                }
+            }
+            DecodeState::Kind => {
+                self.kind = byte;
+                self.crc = crc8(self.crc, byte);
+                self.state = DecodeState::Length;
+            }
+            DecodeState::Length => {
+                self.len = usize::from(byte);
+                self.crc = crc8(self.crc, byte);
+                self.state = match self.len {
+                    0 => DecodeState::Checksum,
+                    len if len <= MAX_PAYLOAD => DecodeState::Payload(0),
+                    _ => DecodeState::Sync,
+                };
+            }
+            DecodeState::Payload(i) => {
+                self.buf[i] = byte;
+                self.crc = crc8(self.crc, byte);
+                self.state = if i + 1 < self.len {
+                    DecodeState::Payload(i + 1)
+                } else {
+                    DecodeState::Checksum
+                };
+            }
+            DecodeState::Checksum => {
+                self.state = DecodeState::Sync;
+                if byte == self.crc {
+                    let payload = &self.buf[..self.len];
+                    return match (self.kind, payload) {
+                        (KIND_MATRIX, _) => Some(Message::Matrix(payload)),
+                        (KIND_BACKLIGHT, &[level]) => Some(Message::Backlight(level)),
+                        _ => None,
+                    };
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Checks that the keys of a half fit in a matrix message.
+struct KeyCount<const ROWS: usize, const COLS: usize>;
+
+impl<const ROWS: usize, const COLS: usize> KeyCount<ROWS, COLS> {
+    const CHECK: () = assert!(
+        ROWS * COLS <= 8 * MAX_PAYLOAD,
+        "too many keys for a matrix message"
+    );
+
+    /// The length of a matrix message.
+    const BYTES: usize = (ROWS * COLS).div_ceil(8);
+}
+
+fn get_bit(bits: &[u8], index: usize) -> bool {
+    bits[index / 8] & (1 << (index % 8)) != 0
+}
+
+/// A scanner on the primary half, which mirrors the state of the keys of the
+/// secondary half as received from the serial link.
+///
+/// At most one matrix message is handled per poll, so that no key events are
+/// lost if several messages were received since the last poll. If no valid
+/// matrix message is received for a few resend intervals, the link is assumed
+/// to be lost, and all of the keys are released, so that they don't get stuck.
+pub struct Remote<Rx, const ROWS: usize, const COLS: usize> {
+    rx: Rx,
+    decoder: Decoder,
+    pressed: [u8; MAX_PAYLOAD],
+    last_pressed: [u8; MAX_PAYLOAD],
+    /// When the last valid matrix message was received, if the link is up.
+    last_frame: Option<Instant>,
+}
+
+impl<Rx, const ROWS: usize, const COLS: usize> Remote<Rx, ROWS, COLS>
+where
+    Rx: serial::Read<u8>,
+{
+    pub fn new(rx: Rx) -> Self {
+        let () = KeyCount::<ROWS, COLS>::CHECK;
+        Self {
+            rx,
+            decoder: Decoder::new(),
+            pressed: [0; MAX_PAYLOAD],
+            last_pressed: [0; MAX_PAYLOAD],
+            last_frame: None,
+        }
+    }
+
+    pub fn free(self) -> Rx {
+        self.rx
+    }
+}
+
+impl<Rx, const ROWS: usize, const COLS: usize> Scanner<ROWS, COLS> for Remote<Rx, ROWS, COLS>
+where
+    Rx: serial::Read<u8>,
+{
+    type Error = Rx::Error;
+
+    fn poll(&mut self, now: Instant) -> Result<(), Self::Error> {
+        self.last_pressed = self.pressed;
+        loop {
+            let byte = match self.rx.read() {
+                Ok(byte) => byte,
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(e)) => return Err(e),
+            };
+            if let Some(Message::Matrix(bits)) = self.decoder.push(byte) {
+                if bits.len() == KeyCount::<ROWS, COLS>::BYTES {
+                    self.pressed[..bits.len()].copy_from_slice(bits);
+                    self.last_frame = Some(now);
+                    return Ok(());
+                }
+            }
+        }
+        let lost = self
+            .last_frame
+            .is_some_and(|last_frame| has_elapsed(now, last_frame, LINK_TIMEOUT));
+        if lost {
+            self.pressed = [0; MAX_PAYLOAD];
+            self.last_frame = None;
+        }
+        Ok(())
+    }
+
+    fn is_pressed(&self, row: usize, col: usize) -> bool {
+        get_bit(&self.pressed, row * COLS + col)
+    }
+
+    fn just_pressed(&self, row: usize, col: usize) -> bool {
+        self.is_pressed(row, col) && !get_bit(&self.last_pressed, row * COLS + col)
+    }
+
+    fn just_released(&self, row: usize, col: usize) -> bool {
+        !self.is_pressed(row, col) && get_bit(&self.last_pressed, row * COLS + col)
+    }
+}
+
+/// A backlight on the primary half, which forwards every level change to the
+/// secondary half.
+///
+/// The [`Backlight`] interface can't report errors, so a level that fails to
+/// be written to the serial link is only applied locally.
+pub struct Forward<B, Tx> {
+    backlight: B,
+    tx: Tx,
+}
+
+impl<B, Tx> Forward<B, Tx>
+where
+    B: Backlight,
+    Tx: serial::Write<u8>,
+{
+    pub fn new(backlight: B, tx: Tx) -> Self {
+        Self { backlight, tx }
+    }
+
+    pub fn free(self) -> (B, Tx) {
+        (self.backlight, self.tx)
+    }
+}
+
+impl<B, Tx> Backlight for Forward<B, Tx>
+where
+    B: Backlight,
+    Tx: serial::Write<u8>,
+{
+    fn num_levels(&self) -> u8 {
+        self.backlight.num_levels()
+    }
+
+    fn level(&self) -> u8 {
+        self.backlight.level()
+    }
+
+    fn set_level(&mut self, level: u8) {
+        self.backlight.set_level(level);
+        let _ = send(&mut self.tx, &Message::Backlight(self.backlight.level()));
+    }
+}
+
+/// The top-level implementation for the secondary half, which sends the state
+/// of its keys to the primary half, and applies the backlight levels forwarded
+/// by it.
+///
+/// This is used instead of a [`System`](crate::system::System) on the secondary
+/// half; [`poll`](Secondary::poll) should be called as often.
+pub struct Secondary<S, B, Tx, Rx, T, const ROWS: usize, const COLS: usize> {
+    scanner: S,
+    backlight: B,
+    tx: Tx,
+    rx: Rx,
+    clock: T,
+    decoder: Decoder,
+    /// When the state of the keys was last sent, if ever.
+    last_send: Option<Instant>,
+}
+
+impl<S, B, Tx, Rx, T, const ROWS: usize, const COLS: usize> Secondary<S, B, Tx, Rx, T, ROWS, COLS>
+where
+    S: Scanner<ROWS, COLS>,
+    B: Backlight,
+    Tx: serial::Write<u8>,
+    Rx: serial::Read<u8>,
+    T: Clock,
+{
+    pub fn new(scanner: S, backlight: B, tx: Tx, rx: Rx, clock: T) -> Self {
+        let () = KeyCount::<ROWS, COLS>::CHECK;
+        Self {
+            scanner,
+            backlight,
+            tx,
+            rx,
+            clock,
+            decoder: Decoder::new(),
+            last_send: None,
+        }
+    }
+
+    pub fn scanner(&mut self) -> &mut S {
+        &mut self.scanner
+    }
+
+    pub fn backlight(&mut self) -> &mut B {
+        &mut self.backlight
+    }
+
+    pub fn poll(&mut self) -> Result<(), SecondaryError<S, Tx, Rx, ROWS, COLS>> {
+        let now = self.clock.now();
+        self.scanner.poll(now).map_err(Error::Scanner)?;
+        self.backlight.poll(now);
+
+        let mut bits = [0u8; MAX_PAYLOAD];
+        let mut changed = false;
+        for row in 0..ROWS {
+            for col in 0..COLS {
+                let index = row * COLS + col;
+                if self.scanner.is_pressed(row, col) {
+                    bits[index / 8] |= 1 << (index % 8);
+                }
+                changed |=
+                    self.scanner.just_pressed(row, col) || self.scanner.just_released(row, col);
+            }
+        }
+        let resend = self
+            .last_send
+            .is_none_or(|last_send| has_elapsed(now, last_send, RESEND_INTERVAL));
+        if changed || resend {
+            let message = Message::Matrix(&bits[..KeyCount::<ROWS, COLS>::BYTES]);
+            send(&mut self.tx, &message).map_err(Error::Write)?;
+            self.last_send = Some(now);
+        }
+
+        loop {
+            let byte = match self.rx.read() {
+                Ok(byte) => byte,
+                Err(nb::Error::WouldBlock) => return Ok(()),
+                Err(nb::Error::Other(e)) => return Err(Error::Read(e)),
+            };
+            if let Some(Message::Backlight(level)) = self.decoder.push(byte) {
+                self.backlight.set_level(level);
+            }
+        }
+    }
+}
+
+pub enum Error<S, T, R> {
+    Scanner(S),
+    Write(T),
+    Read(R),
+}
+
+/// The [`Error`] returned by [`Secondary::poll`].
+pub type SecondaryError<S, Tx, Rx, const ROWS: usize, const COLS: usize> = Error<
+    <S as Scanner<ROWS, COLS>>::Error,
+    <Tx as serial::Write<u8>>::Error,
+    <Rx as serial::Read<u8>>::Error,
+>;
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::time::MockClock;
+
+    use core::cell::RefCell;
+    use core::convert::Infallible;
+    use embedded_hal_mock::pin::{Mock, State, Transaction};
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    /// One direction of an in-memory serial link.
+    #[derive(Clone, Default)]
+    struct Loopback(Rc<RefCell<VecDeque<u8>>>);
+
+    impl serial::Write<u8> for Loopback {
+        type Error = Infallible;
+
+        fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+            self.0.borrow_mut().push_back(word);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    impl serial::Read<u8> for Loopback {
+        type Error = Infallible;
+
+        fn read(&mut self) -> nb::Result<u8, Self::Error> {
+            self.0.borrow_mut().pop_front().ok_or(nb::Error::WouldBlock)
+        }
+    }
+
+    /// A scanner whose keys are pressed by the test.
+    #[derive(Default)]
+    struct FakeScanner {
+        pressed: [[bool; 3]; 2],
+        last_pressed: [[bool; 3]; 2],
+        next: [[bool; 3]; 2],
+    }
+
+    impl Scanner<2, 3> for FakeScanner {
+        type Error = Infallible;
+
+        fn poll(&mut self, now: Instant) -> Result<(), Self::Error> {
+            let _ = now;
+            self.last_pressed = self.pressed;
+            self.pressed = self.next;
+            Ok(())
+        }
+
+        fn is_pressed(&self, row: usize, col: usize) -> bool {
+            self.pressed[row][col]
+        }
+
+        fn just_pressed(&self, row: usize, col: usize) -> bool {
+            self.pressed[row][col] && !self.last_pressed[row][col]
+        }
+
+        fn just_released(&self, row: usize, col: usize) -> bool {
+            !self.pressed[row][col] && self.last_pressed[row][col]
+        }
+    }
+
+    struct FakeBacklight(u8);
+
+    impl Backlight for FakeBacklight {
+        fn num_levels(&self) -> u8 {
+            4
+        }
+
+        fn level(&self) -> u8 {
+            self.0
+        }
+
+        fn set_level(&mut self, level: u8) {
+            self.0 = level.min(3);
+        }
+    }
+
+    type TestSecondary =
+        Secondary<FakeScanner, FakeBacklight, Loopback, Loopback, fn() -> Instant, 2, 3>;
+
+    type Halves = (
+        Remote<Loopback, 2, 3>,
+        Forward<FakeBacklight, Loopback>,
+        TestSecondary,
+    );
+
+    fn connect() -> Halves {
+        let to_primary = Loopback::default();
+        let to_secondary = Loopback::default();
+        (
+            Remote::new(to_primary.clone()),
+            Forward::new(FakeBacklight(0), to_secondary.clone()),
+            Secondary::new(
+                FakeScanner::default(),
+                FakeBacklight(0),
+                to_primary,
+                to_secondary,
+                || Instant::from_ticks(0),
+            ),
+        )
+    }
+
+    #[test]
+    fn remote_keys() {
+        let (mut remote, _, mut secondary) = connect();
+
+        secondary.poll().ok().unwrap();
+        remote.poll(Instant::from_ticks(0)).unwrap();
+        assert!(!remote.is_pressed(1, 2));
+
+        secondary.scanner().next[1][2] = true;
+        secondary.poll().ok().unwrap();
+        remote.poll(Instant::from_ticks(0)).unwrap();
+        assert!(remote.just_pressed(1, 2));
+        assert!(!remote.just_pressed(0, 0));
+
+        // Nothing changed, so nothing is sent.
+        secondary.poll().ok().unwrap();
+        remote.poll(Instant::from_ticks(0)).unwrap();
+        assert!(remote.is_pressed(1, 2));
+        assert!(!remote.just_pressed(1, 2));
+
+        secondary.scanner().next[1][2] = false;
+        secondary.scanner().next[0][1] = true;
+        secondary.poll().ok().unwrap();
+        remote.poll(Instant::from_ticks(0)).unwrap();
+        assert!(remote.just_released(1, 2));
+        assert!(remote.just_pressed(0, 1));
+    }
+
+    #[test]
+    fn one_message_per_poll() {
+        let (mut remote, _, mut secondary) = connect();
+
+        secondary.scanner().next[0][0] = true;
+        secondary.poll().ok().unwrap();
+        secondary.scanner().next[0][0] = false;
+        secondary.poll().ok().unwrap();
+
+        remote.poll(Instant::from_ticks(0)).unwrap();
+        assert!(remote.just_pressed(0, 0));
+        remote.poll(Instant::from_ticks(0)).unwrap();
+        assert!(remote.just_released(0, 0));
+    }
+
+    #[test]
+    fn resend_interval() {
+        let to_primary = Loopback::default();
+        let clock = Rc::new(MockClock::new());
+        let mut secondary = Secondary::<_, _, _, _, _, 2, 3>::new(
+            FakeScanner::default(),
+            FakeBacklight(0),
+            to_primary.clone(),
+            Loopback::default(),
+            {
+                let clock = clock.clone();
+                move || clock.now()
+            },
+        );
+
+        secondary.poll().ok().unwrap();
+        assert!(!to_primary.0.borrow().is_empty());
+        to_primary.0.borrow_mut().clear();
+
+        clock.advance(Duration::millis(999));
+        secondary.poll().ok().unwrap();
+        assert!(to_primary.0.borrow().is_empty());
+
+        clock.advance(Duration::millis(1));
+        secondary.poll().ok().unwrap();
+        assert!(!to_primary.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn link_timeout() {
+        let (mut remote, _, mut secondary) = connect();
+
+        secondary.scanner().next[1][2] = true;
+        secondary.poll().ok().unwrap();
+        remote.poll(Instant::from_ticks(0)).unwrap();
+        assert!(remote.just_pressed(1, 2));
+
+        // The secondary half stops sending, e.g. because it was unplugged.
+        remote.poll(Instant::from_ticks(2999)).unwrap();
+        assert!(remote.is_pressed(1, 2));
+        remote.poll(Instant::from_ticks(3000)).unwrap();
+        assert!(remote.just_released(1, 2));
+
+        // The key is pressed again once the link comes back.
+        secondary.scanner().next[0][0] = true;
+        secondary.poll().ok().unwrap();
+        remote.poll(Instant::from_ticks(3500)).unwrap();
+        assert!(remote.just_pressed(1, 2));
+        assert!(remote.just_pressed(0, 0));
+    }
+
+    #[test]
+    fn role_detect() {
+        let mut vbus = Mock::new(&[Transaction::get(State::High), Transaction::get(State::Low)]);
+        assert!(Role::detect(&vbus).unwrap() == Role::Primary);
+        assert!(Role::detect(&vbus).unwrap() == Role::Secondary);
+        vbus.done();
+    }
+
+    #[test]
+    fn backlight_forwarding() {
+        let (_, mut forward, mut secondary) = connect();
+
+        forward.increase();
+        forward.increase();
+        secondary.poll().ok().unwrap();
+        assert!(secondary.backlight().level() == 2);
+
+        forward.set_level(10);
+        secondary.poll().ok().unwrap();
+        assert!(forward.level() == 3);
+        assert!(secondary.backlight().level() == 3);
+    }
+
+    #[test]
+    fn corrupted_frame() {
+        let mut tx = Loopback::default();
+        let mut remote = Remote::<_, 2, 3>::new(tx.clone());
+
+        send(&mut tx, &Message::Matrix(&[0b1])).unwrap();
+        // Flip a bit of the payload.
+        tx.0.borrow_mut()[3] ^= 0b10;
+        send(&mut tx, &Message::Matrix(&[0b100])).unwrap();
+
+        remote.poll(Instant::from_ticks(0)).unwrap();
+        assert!(!remote.is_pressed(0, 0));
+        assert!(!remote.is_pressed(0, 1));
+        assert!(remote.is_pressed(0, 2));
+    }
+}
@@ -0,0 +1,104 @@
+//! Settings that persist across resets.
+//!
+//! Keyboards with non-volatile memory, like an EEPROM, can provide it as a
+//! [`Storage`] backend. The layout of the settings in storage is defined here,
+//! so that every backend only needs to read and write bytes.
+
+use core::convert::Infallible;
+
+/// Non-volatile memory, addressed by byte.
+///
+/// For keyboards without non-volatile memory, the type [`NoStorage`] provides
+/// an implementation that never holds any settings.
+pub trait Storage {
+    type Error;
+
+    /// Reads `buf.len()` bytes, starting at `address`.
+    fn read(&mut self, address: usize, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Writes `data`, starting at `address`.
+    fn write(&mut self, address: usize, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// A storage without any memory, which reads as erased.
+pub struct NoStorage;
+
+impl Storage for NoStorage {
+    type Error = Infallible;
+
+    fn read(&mut self, address: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+        let _ = address;
+        buf.fill(0xff);
+        Ok(())
+    }
+
+    fn write(&mut self, address: usize, data: &[u8]) -> Result<(), Self::Error> {
+        let _ = (address, data);
+        Ok(())
+    }
+}
+
+/// The address of the default layer, which is stored along with its
+/// complement, so that erased or corrupted memory is not mistaken for a
+/// layer.
+const DEFAULT_LAYER: usize = 0;
+
+/// Reads the default layer, if one has been saved.
+pub fn load_default_layer<S: Storage>(storage: &mut S) -> Result<Option<u8>, S::Error> {
+    let mut buf = [0; 2];
+    storage.read(DEFAULT_LAYER, &mut buf)?;
+    let [layer, check] = buf;
+    Ok((check == !layer).then_some(layer))
+}
+
+/// Saves the default layer, unless it is already saved, to avoid wearing out
+/// the memory.
+pub fn save_default_layer<S: Storage>(storage: &mut S, layer: u8) -> Result<(), S::Error> {
+    if load_default_layer(storage)? != Some(layer) {
+        storage.write(DEFAULT_LAYER, &[layer, !layer])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Memory that counts its writes.
+    struct FakeStorage {
+        memory: [u8; 4],
+        writes: usize,
+    }
+
+    impl Storage for FakeStorage {
+        type Error = Infallible;
+
+        fn read(&mut self, address: usize, buf: &mut [u8]) -> Result<(), Self::Error> {
+            buf.copy_from_slice(&self.memory[address..][..buf.len()]);
+            Ok(())
+        }
+
+        fn write(&mut self, address: usize, data: &[u8]) -> Result<(), Self::Error> {
+            self.memory[address..][..data.len()].copy_from_slice(data);
+            self.writes += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn default_layer() {
+        let mut storage = FakeStorage {
+            memory: [0xff; 4],
+            writes: 0,
+        };
+        assert_eq!(load_default_layer(&mut storage), Ok(None));
+
+        save_default_layer(&mut storage, 2).unwrap();
+        save_default_layer(&mut storage, 2).unwrap();
+        assert_eq!(load_default_layer(&mut storage), Ok(Some(2)));
+        assert_eq!(storage.writes, 1);
+
+        storage.memory[1] = 0;
+        assert_eq!(load_default_layer(&mut storage), Ok(None));
+    }
+}
@@ -1,16 +1,22 @@
 use fullhouse::Deque;
 
 use crate::backlight::Backlight;
+use crate::encoder::Encoder;
 use crate::keyboard::Keyboard;
 use crate::keycode::{KeyAction, Keycode, SystemKeycode};
 use crate::keymap::Keymap;
 use crate::mutex::Mutex;
-use crate::scanner::Scanner;
+use crate::power::{Power, SleepMode};
+use crate::scanner::{KeyEvent, Scanner};
+use crate::storage::{self, Storage};
+use crate::tap_hold::{self, TapHold};
+use crate::time::{Clock, Duration, Instant};
 use crate::uplink::Uplink;
 
 #[derive(Clone)]
 enum Request {
     ClearKeyboardButMods,
+    SaveDefaultLayer(u8),
 }
 
 static REQUESTS: Mutex<Deque<Request, 16>> = Mutex::new(Deque::new());
@@ -25,33 +31,149 @@ pub fn clear_keyboard_but_mods() {
     try_send(Request::ClearKeyboardButMods);
 }
 
+/// Saves the default layer to the keyboard's storage, to be restored by
+/// [`System::new`] after a reset.
+pub fn save_default_layer(layer: u8) {
+    try_send(Request::SaveDefaultLayer(layer));
+}
+
+/// The number of key events that can be queued at a time during a poll, and
+/// held back while a tap-hold key is undecided.
+///
+/// If a single scan emits more events than this, they are handled in batches.
+/// If the tap-hold buffer fills up, undecided keys are decided early instead.
+pub const EVENT_QUEUE_LEN: usize = 16;
+
 /// Top-level system implementation that polls components and dispatches events.
-pub struct System<K, B, const ROWS: usize, const COLS: usize> {
+pub struct System<K, B, T, const ROWS: usize, const COLS: usize> {
     keymap: K,
     keyboard: B,
+    clock: T,
+    /// A batch of the key events emitted by the scanner during a poll, in
+    /// order.
+    events: Deque<KeyEvent, EVENT_QUEUE_LEN>,
+    /// Key events waiting to be dispatched once any tap-hold keys before them
+    /// are decided.
+    tap_hold: TapHold<EVENT_QUEUE_LEN>,
+    /// The keycode tapped by an encoder step during the last poll, which is
+    /// released during the next poll.
+    encoder_tap: Option<Keycode>,
+    /// How long to wait after the last key event before going to sleep.
+    idle_timeout: Option<Duration>,
+    last_activity: Instant,
 }
 
-impl<K, B, const ROWS: usize, const COLS: usize> System<K, B, ROWS, COLS>
+impl<K, B, T, const ROWS: usize, const COLS: usize> System<K, B, T, ROWS, COLS>
 where
     K: Keymap<ROWS, COLS>,
     B: Keyboard<ROWS, COLS>,
+    T: Clock,
 {
-    pub fn new(keymap: K, keyboard: B) -> Self {
-        Self { keymap, keyboard }
+    /// Creates the system, restoring the default layer of the keymap from the
+    /// keyboard's storage, if one has been saved.
+    pub fn new(mut keymap: K, mut keyboard: B, clock: T) -> Self {
+        // If the storage can't be read, the keymap's own default is used.
+        if let Ok(Some(layer)) = storage::load_default_layer(keyboard.storage()) {
+            keymap.set_default_layer(layer);
+        }
+        Self {
+            keymap,
+            keyboard,
+            events: Deque::new(),
+            tap_hold: TapHold::new(tap_hold::Config::default()),
+            encoder_tap: None,
+            idle_timeout: None,
+            last_activity: clock.now(),
+            clock,
+        }
+    }
+
+    pub fn clock(&self) -> &T {
+        &self.clock
+    }
+
+    /// Sets how tap-hold keys are decided.
+    pub fn set_tap_hold_config(&mut self, config: tap_hold::Config) {
+        self.tap_hold.set_config(config);
+    }
+
+    /// Sets how long the keyboard stays awake after the last key event, before
+    /// going to sleep until the next key press. `None`, the default, disables
+    /// sleeping while the host is active.
+    ///
+    /// Regardless of this setting, the keyboard goes to sleep whenever the
+    /// host suspends the uplink. Sleeping requires a scanner that supports
+    /// [`prepare_wake()`](Scanner::prepare_wake), and is skipped while any keys
+    /// are held down, still being debounced (see [`Scanner::is_idle`]), or held
+    /// back by a tap-hold key, tap dance or combo that hasn't been decided yet.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
     }
 
     pub fn poll(
         &mut self,
-    ) -> Result<(), Error<<B::Scanner as Scanner<ROWS, COLS>>::Error, <B::Uplink as Uplink>::Error>>
-    {
-        self.keyboard.scanner().poll().map_err(Error::Scanner)?;
-        for row in 0..ROWS {
-            for col in 0..COLS {
-                if self.keyboard.scanner().just_pressed(row, col) {
-                    self.key_event(self.keymap.get(row, col), KeyAction::Pressed)?;
+    ) -> Result<
+        (),
+        Error<
+            <B::Scanner as Scanner<ROWS, COLS>>::Error,
+            <B::Uplink as Uplink>::Error,
+            <B::Encoder as Encoder>::Error,
+            <B::Power as Power>::Error,
+            <B::Storage as Storage>::Error,
+        >,
+    > {
+        let now = self.clock.now();
+        self.keymap.poll(now);
+        self.keyboard.backlight().poll(now);
+        self.keyboard.scanner().poll(now).map_err(Error::Scanner)?;
+        // The scanner's events are taken in batches that fit in the queue,
+        // skipping the ones that were already handled, so that none of them
+        // are dropped however many keys changed.
+        let mut handled = 0;
+        loop {
+            let events = &mut self.events;
+            let mut index = 0;
+            self.keyboard.scanner().for_each_event(now, |event| {
+                if index >= handled {
+                    let _ = events.push_back(event);
+                }
+                index += 1;
+            });
+            if index == handled {
+                break;
+            }
+            self.last_activity = now;
+            while let Some(mut event) = self.events.pop_front() {
+                handled += 1;
+                // Taking keycode events from a full tap-hold buffer decides
+                // the keys in it, which makes room.
+                while let Err(rejected) = self.tap_hold.push(event) {
+                    event = rejected;
+                    if let Some((keycode, action)) = self.tap_hold.next(now, &self.keymap) {
+                        self.key_event(keycode, action)?;
+                        self.last_activity = now;
+                    }
                 }
-                if self.keyboard.scanner().just_released(row, col) {
-                    self.key_event(self.keymap.get(row, col), KeyAction::Released)?;
+            }
+        }
+        while let Some((keycode, action)) = self.tap_hold.next(now, &self.keymap) {
+            self.key_event(keycode, action)?;
+            self.last_activity = now;
+        }
+        self.keyboard.encoder().poll().map_err(Error::Encoder)?;
+        // Only one encoder step is tapped at a time, and it is released
+        // during the next poll, so that the host sees both events. Any other
+        // steps stay recorded by the encoders until then.
+        if let Some(keycode) = self.encoder_tap.take() {
+            self.key_event(keycode, KeyAction::Released)?;
+        } else {
+            for i in 0..B::Encoder::COUNT {
+                if let Some(direction) = self.keyboard.encoder().take_step(i) {
+                    let keycode = self.keymap.get_encoder(i, direction);
+                    self.key_event(keycode, KeyAction::Pressed)?;
+                    self.encoder_tap = Some(keycode);
+                    self.last_activity = now;
+                    break;
                 }
             }
         }
@@ -64,17 +186,62 @@ where
                         .clear_keyboard_but_mods()
                         .map_err(Error::Uplink)?;
                 }
+                Request::SaveDefaultLayer(layer) => {
+                    storage::save_default_layer(self.keyboard.storage(), layer)
+                        .map_err(Error::Storage)?;
+                }
+            }
+        }
+        if let Some(mode) = self.sleep_mode(now) {
+            if self
+                .keyboard
+                .scanner()
+                .prepare_wake()
+                .map_err(Error::Scanner)?
+            {
+                self.keyboard.power().sleep(mode).map_err(Error::Power)?;
             }
         }
         Ok(())
     }
 
+    /// Whether the keyboard should go to sleep, and how deeply.
+    fn sleep_mode(&mut self, now: Instant) -> Option<SleepMode> {
+        let scanner = self.keyboard.scanner();
+        let any_pressed = (0..ROWS).any(|row| (0..COLS).any(|col| scanner.is_pressed(row, col)));
+        // Keys held back by the tap-hold engine or the debouncer are only sent
+        // after a timeout, which would be delayed until the next wake up.
+        if any_pressed
+            || !scanner.is_idle()
+            || self.encoder_tap.is_some()
+            || !self.tap_hold.is_idle()
+        {
+            return None;
+        }
+        if self.keyboard.uplink().is_suspended() {
+            return Some(SleepMode::Suspend);
+        }
+        let timeout = self.idle_timeout?;
+        let idle = now
+            .checked_duration_since(self.last_activity)
+            .is_none_or(|idle| idle >= timeout);
+        idle.then_some(SleepMode::Idle)
+    }
+
     fn key_event(
         &mut self,
         keycode: Keycode,
         action: KeyAction,
-    ) -> Result<(), Error<<B::Scanner as Scanner<ROWS, COLS>>::Error, <B::Uplink as Uplink>::Error>>
-    {
+    ) -> Result<
+        (),
+        Error<
+            <B::Scanner as Scanner<ROWS, COLS>>::Error,
+            <B::Uplink as Uplink>::Error,
+            <B::Encoder as Encoder>::Error,
+            <B::Power as Power>::Error,
+            <B::Storage as Storage>::Error,
+        >,
+    > {
         match keycode {
             Keycode::System(SystemKeycode::BacklightDown) if action.is_pressed() => {
                 self.keyboard.backlight().decrease();
@@ -96,7 +263,244 @@ where
     }
 }
 
-pub enum Error<S, U> {
+pub enum Error<S, U, E, P, St> {
     Scanner(S),
     Uplink(U),
+    Encoder(E),
+    Power(P),
+    Storage(St),
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::backlight::NoBacklight;
+    use crate::encoder::NoEncoder;
+    use crate::keycode::qmk::*;
+    use crate::keymap::{Dance, Layered};
+    use crate::storage::NoStorage;
+    use crate::time::MockClock;
+
+    use core::convert::Infallible;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    static LAYERS: [[[Keycode; 6]; 3]; 1] = [[
+        [KC_A, KC_B, KC_C, KC_D, KC_E, KC_F],
+        [KC_G, KC_H, KC_I, KC_J, KC_K, KC_L],
+        [KC_M, KC_N, KC_O, KC_P, KC_Q, KC_R],
+    ]];
+
+    /// A scanner whose keys are pressed by the test.
+    #[derive(Default)]
+    struct FakeScanner {
+        pressed: [[bool; 6]; 3],
+        last_pressed: [[bool; 6]; 3],
+        next: [[bool; 6]; 3],
+        settling: bool,
+    }
+
+    impl Scanner<3, 6> for FakeScanner {
+        type Error = Infallible;
+
+        fn poll(&mut self, now: Instant) -> Result<(), Self::Error> {
+            let _ = now;
+            self.last_pressed = self.pressed;
+            self.pressed = self.next;
+            Ok(())
+        }
+
+        fn is_pressed(&self, row: usize, col: usize) -> bool {
+            self.pressed[row][col]
+        }
+
+        fn just_pressed(&self, row: usize, col: usize) -> bool {
+            self.pressed[row][col] && !self.last_pressed[row][col]
+        }
+
+        fn just_released(&self, row: usize, col: usize) -> bool {
+            !self.pressed[row][col] && self.last_pressed[row][col]
+        }
+
+        fn prepare_wake(&mut self) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+
+        fn is_idle(&self) -> bool {
+            !self.settling
+        }
+    }
+
+    /// An uplink that records the key events sent to the host.
+    #[derive(Default)]
+    struct FakeUplink {
+        events: Vec<(Keycode, KeyAction)>,
+    }
+
+    impl Uplink for FakeUplink {
+        type Error = Infallible;
+
+        fn poll(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn key_event(&mut self, keycode: Keycode, action: KeyAction) -> Result<(), Self::Error> {
+            self.events.push((keycode, action));
+            Ok(())
+        }
+
+        fn clear_keyboard_but_mods(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    /// Power management that records when the keyboard goes to sleep.
+    #[derive(Default)]
+    struct FakePower {
+        sleeps: Vec<SleepMode>,
+    }
+
+    impl Power for FakePower {
+        type Error = Infallible;
+
+        fn sleep(&mut self, mode: SleepMode) -> Result<(), Self::Error> {
+            self.sleeps.push(mode);
+            Ok(())
+        }
+    }
+
+    struct FakeKeyboard {
+        scanner: FakeScanner,
+        uplink: FakeUplink,
+        backlight: NoBacklight,
+        encoder: NoEncoder,
+        power: FakePower,
+        storage: NoStorage,
+    }
+
+    impl Keyboard<3, 6> for FakeKeyboard {
+        type Scanner = FakeScanner;
+        type Uplink = FakeUplink;
+        type Backlight = NoBacklight;
+        type Encoder = NoEncoder;
+        type Power = FakePower;
+        type Storage = NoStorage;
+
+        fn scanner(&mut self) -> &mut Self::Scanner {
+            &mut self.scanner
+        }
+
+        fn uplink(&mut self) -> &mut Self::Uplink {
+            &mut self.uplink
+        }
+
+        fn backlight(&mut self) -> &mut Self::Backlight {
+            &mut self.backlight
+        }
+
+        fn encoder(&mut self) -> &mut Self::Encoder {
+            &mut self.encoder
+        }
+
+        fn power(&mut self) -> &mut Self::Power {
+            &mut self.power
+        }
+
+        fn storage(&mut self) -> &mut Self::Storage {
+            &mut self.storage
+        }
+    }
+
+    type TestSystem<'a> =
+        System<Layered<3, 6, 1>, FakeKeyboard, Box<dyn Fn() -> Instant + 'a>, 3, 6>;
+
+    fn system(clock: &MockClock, keymap: Layered<3, 6, 1>) -> TestSystem<'_> {
+        let clock = Box::new(|| clock.now());
+        let keyboard = FakeKeyboard {
+            scanner: FakeScanner::default(),
+            uplink: FakeUplink::default(),
+            backlight: NoBacklight,
+            encoder: NoEncoder,
+            power: FakePower::default(),
+            storage: NoStorage,
+        };
+        System::new(keymap, keyboard, clock)
+    }
+
+    /// Sets the state of the first key, and polls at the given time.
+    fn poll_first(system: &mut TestSystem, clock: &MockClock, pressed: bool, ticks: u32) {
+        clock.set(Instant::from_ticks(ticks));
+        system.keyboard.scanner.next[0][0] = pressed;
+        assert!(system.poll().is_ok());
+    }
+
+    /// Sets the state of every key, and polls.
+    fn poll(system: &mut TestSystem, pressed: bool) {
+        system.keyboard.scanner.next = [[pressed; 6]; 3];
+        assert!(system.poll().is_ok());
+    }
+
+    #[test]
+    fn more_events_than_queue() {
+        let clock = MockClock::new();
+        let mut system = system(&clock, Layered::new(&LAYERS));
+        let keycodes: Vec<Keycode> = LAYERS[0].iter().flatten().copied().collect();
+        assert!(keycodes.len() > EVENT_QUEUE_LEN);
+
+        poll(&mut system, true);
+        let pressed: Vec<_> = keycodes.iter().map(|&k| (k, KeyAction::Pressed)).collect();
+        assert!(system.keyboard.uplink.events == pressed);
+        system.keyboard.uplink.events.clear();
+
+        poll(&mut system, false);
+        let released: Vec<_> = keycodes.iter().map(|&k| (k, KeyAction::Released)).collect();
+        assert!(system.keyboard.uplink.events == released);
+    }
+
+    #[test]
+    fn awake_while_dancing() {
+        static LAYERS: [[[Keycode; 6]; 3]; 1] = [[[TD(0); 6]; 3]];
+        static DANCES: [Dance; 1] = [Dance {
+            taps: &[KC_A, KC_B],
+            holds: &[],
+        }];
+        let clock = MockClock::new();
+        let mut system = system(&clock, Layered::new(&LAYERS).with_dances(&DANCES));
+        system.set_idle_timeout(Some(Duration::millis(100)));
+
+        poll_first(&mut system, &clock, true, 0);
+        poll_first(&mut system, &clock, false, 10);
+        // The dance is still waiting for another tap.
+        poll_first(&mut system, &clock, false, 150);
+        assert!(system.keyboard.uplink.events.is_empty());
+        assert!(system.keyboard.power.sleeps.is_empty());
+
+        poll_first(&mut system, &clock, false, 250);
+        assert!(
+            system.keyboard.uplink.events
+                == [(KC_A, KeyAction::Pressed), (KC_A, KeyAction::Released)]
+        );
+        assert!(system.keyboard.power.sleeps.is_empty());
+
+        poll_first(&mut system, &clock, false, 350);
+        assert!(system.keyboard.power.sleeps == [SleepMode::Idle]);
+    }
+
+    #[test]
+    fn awake_while_debouncing() {
+        let clock = MockClock::new();
+        let mut system = system(&clock, Layered::new(&LAYERS));
+        system.set_idle_timeout(Some(Duration::millis(100)));
+
+        // A press that the scanner has seen, but not reported yet.
+        system.keyboard.scanner.settling = true;
+        poll_first(&mut system, &clock, false, 150);
+        assert!(system.keyboard.power.sleeps.is_empty());
+
+        system.keyboard.scanner.settling = false;
+        poll_first(&mut system, &clock, false, 160);
+        assert!(system.keyboard.power.sleeps == [SleepMode::Idle]);
+    }
 }
@@ -0,0 +1,726 @@
+//! Keys that do one thing when tapped, and another when held.
+//!
+//! A tap-hold key, like [`MT()`](crate::keycode::qmk::MT) or
+//! [`LT()`](crate::keycode::qmk::LT), is undecided when it is pressed. Until
+//! it is decided to be a tap or a hold, the events of the keys pressed after it
+//! are held back; then, they are replayed in their original order, so that
+//! they are looked up on the right layer and sent with the right modifiers.
+//!
+//! Tap dances ([`TD()`](crate::keycode::qmk::TD)) are decided the same way:
+//! a dance continues while its key is tapped again within the tapping term,
+//! and then sends the keycode of its [`Dance`] for the number of taps.
+//!
+//! [`Combo`](crate::combo::Combo)s are also matched here, before the keys
+//! are looked up: while the keys pressed so far could still complete a combo,
+//! they are held back for up to the combo term.
+
+use fullhouse::Deque;
+
+use crate::combo::{match_combo, ComboMatch};
+use crate::keycode::qmk::{KC_NO, MO};
+use crate::keycode::{HidKeycode, Hold, KeyAction, Keycode, TapHoldKeycode};
+use crate::keymap::{Dance, Keymap};
+use crate::scanner::KeyEvent;
+use crate::time::{elapsed, Duration, Instant};
+
+/// How tap-hold keys are decided.
+///
+/// By default, a tap-hold key is a hold if it is held for the tapping term,
+/// and a tap otherwise. The other options make it a hold sooner, when other
+/// keys are used while it is held, or a tap in more cases.
+#[derive(Clone, Copy)]
+pub struct Config {
+    /// How long a key must be held to be a hold.
+    pub tapping_term: Duration,
+    /// Makes the key a hold as soon as another key is pressed.
+    pub hold_on_other_key_press: bool,
+    /// Makes the key a hold as soon as another key is pressed and released.
+    pub permissive_hold: bool,
+    /// Also taps the key when it is released after being a hold, if no other
+    /// keys were pressed since it was pressed.
+    pub retro_tapping: bool,
+    /// Pressing a key again within this time after tapping it is always a
+    /// tap, so that holding it repeats the tapped keycode. Zero disables this.
+    pub quick_tap_term: Duration,
+    /// How soon after the first key of a combo all of its keys must be
+    /// pressed.
+    pub combo_term: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            tapping_term: Duration::millis(200),
+            hold_on_other_key_press: false,
+            permissive_hold: false,
+            retro_tapping: false,
+            quick_tap_term: Duration::millis(200),
+            combo_term: Duration::millis(50),
+        }
+    }
+}
+
+/// The modifier keycodes, in the order of the bits of [`Hold::Modifiers`].
+const MODIFIERS: [HidKeycode; 8] = [
+    HidKeycode::LeftControl,
+    HidKeycode::LeftShift,
+    HidKeycode::LeftAlt,
+    HidKeycode::LeftGui,
+    HidKeycode::RightControl,
+    HidKeycode::RightShift,
+    HidKeycode::RightAlt,
+    HidKeycode::RightGui,
+];
+
+/// The most keycode events that can be produced by a single key event: the
+/// release of every modifier, followed by a retro tap.
+const OUTPUT_LEN: usize = MODIFIERS.len() + 2;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Decision {
+    Tap,
+    Hold,
+}
+
+/// A tap-hold key that has not been decided yet.
+#[derive(Clone, Copy)]
+struct Pending {
+    row: usize,
+    col: usize,
+    keycode: TapHoldKeycode,
+    pressed_at: Instant,
+}
+
+/// A tap dance that has not finished yet.
+#[derive(Clone, Copy)]
+struct Dancing {
+    row: usize,
+    col: usize,
+    dance: Dance,
+    /// The number of times the key has been pressed.
+    count: u8,
+    pressed: bool,
+    /// When the key was last pressed or released.
+    since: Instant,
+}
+
+/// What a decided key sends until it is released.
+#[derive(Clone, Copy)]
+enum Resolved {
+    TapHold(TapHoldKeycode, Decision),
+    Dance(Keycode),
+    /// A key of a combo, which releases the combo's keycode if it's the
+    /// first one released. The combo is identified by its first key.
+    Combo(Keycode, (usize, usize)),
+    /// A key of a combo that has already been released.
+    Suppressed,
+}
+
+/// A decided key that is still pressed.
+#[derive(Clone, Copy)]
+struct Active {
+    row: usize,
+    col: usize,
+    resolved: Resolved,
+    /// Whether another key was pressed since this key was decided.
+    interrupted: bool,
+}
+
+/// The tap-hold engine, which sits between the scanner and the keymap.
+///
+/// Key events are added with [`push()`](Self::push), and the resulting
+/// keycode events are taken with [`next()`](Self::next). Up to `N` key events
+/// can be held back while a tap-hold key is undecided, and up to `N` decided
+/// keys can be held down at once; further keys are sent as they are, without
+/// tap-hold, tap dance or combo handling.
+pub struct TapHold<const N: usize> {
+    config: Config,
+    /// Key events that haven't been handled yet, in order.
+    buffer: [Option<KeyEvent>; N],
+    pending: Option<Pending>,
+    dancing: Option<Dancing>,
+    active: [Option<Active>; N],
+    /// Keycode events that are ready to be taken.
+    output: Deque<(Keycode, KeyAction), OUTPUT_LEN>,
+    /// The last tap-hold key that was a tap, and when it was released.
+    last_tap: Option<(usize, usize, Instant)>,
+}
+
+impl<const N: usize> TapHold<N> {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            buffer: [None; N],
+            pending: None,
+            dancing: None,
+            active: [None; N],
+            output: Deque::new(),
+            last_tap: None,
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
+
+    /// Whether no key events are held back, so that nothing is waiting for a
+    /// timeout to be decided.
+    pub fn is_idle(&self) -> bool {
+        self.buffer[0].is_none() && self.pending.is_none() && self.dancing.is_none()
+    }
+
+    /// Adds a key event after the ones that haven't been handled yet, or
+    /// returns it if the buffer is full.
+    ///
+    /// While the buffer is full, [`next()`](Self::next) decides undecided keys
+    /// right away, so taking keycode events from it always makes room.
+    pub fn push(&mut self, event: KeyEvent) -> Result<(), KeyEvent> {
+        match self.buffer.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(event);
+                Ok(())
+            }
+            None => Err(event),
+        }
+    }
+
+    /// Takes the next keycode event, if it has been decided by the time
+    /// `now`.
+    ///
+    /// Keys are looked up in the keymap as they are replayed, so the keycode
+    /// events of each key should be handled by the keymap before taking the
+    /// next one.
+    pub fn next<K, const ROWS: usize, const COLS: usize>(
+        &mut self,
+        now: Instant,
+        keymap: &K,
+    ) -> Option<(Keycode, KeyAction)>
+    where
+        K: Keymap<ROWS, COLS>,
+    {
+        loop {
+            if let Some(output) = self.output.pop_front() {
+                return Some(output);
+            }
+            if let Some(pending) = self.pending {
+                let decision = self.decide(&pending, now)?;
+                self.pending = None;
+                self.activate(pending.row, pending.col, pending.keycode, decision);
+                continue;
+            }
+            if let Some(dancing) = self.dancing {
+                if !self.advance_dance(dancing, now) {
+                    return None;
+                }
+                continue;
+            }
+            if let Some(first) = self.buffer[0].filter(|event| event.action.is_pressed()) {
+                let term = self.config.combo_term;
+                match match_combo(keymap.combos(), &self.buffer, now, term, keymap) {
+                    ComboMatch::Wait => return None,
+                    ComboMatch::Found(keycode, len) if len <= self.free_slots() => {
+                        for _ in 0..len {
+                            let event = self.pop_front()?;
+                            let id = (first.row, first.col);
+                            self.add_active(event.row, event.col, Resolved::Combo(keycode, id));
+                        }
+                        let _ = self.output.push_back((keycode, KeyAction::Pressed));
+                        continue;
+                    }
+                    ComboMatch::Found(..) | ComboMatch::None => {}
+                }
+            }
+            let event = self.pop_front()?;
+            match event.action {
+                KeyAction::Pressed => {
+                    for active in self.active.iter_mut().flatten() {
+                        active.interrupted = true;
+                    }
+                    let quick_tap = self.last_tap.take().is_some_and(|(row, col, released_at)| {
+                        row == event.row
+                            && col == event.col
+                            && elapsed(released_at, event.timestamp) < self.config.quick_tap_term
+                    });
+                    // Decided keys need a slot to be released later.
+                    let room = self.free_slots() > 0;
+                    match keymap.get(event.row, event.col) {
+                        Keycode::TapHold(keycode) if room && quick_tap => {
+                            self.activate(event.row, event.col, keycode, Decision::Tap);
+                        }
+                        Keycode::TapHold(keycode) if room => {
+                            self.pending = Some(Pending {
+                                row: event.row,
+                                col: event.col,
+                                keycode,
+                                pressed_at: event.timestamp,
+                            });
+                        }
+                        Keycode::TapDance(index) if room && keymap.get_dance(index).is_some() => {
+                            self.dancing = keymap.get_dance(index).map(|dance| Dancing {
+                                row: event.row,
+                                col: event.col,
+                                dance,
+                                count: 1,
+                                pressed: true,
+                                since: event.timestamp,
+                            });
+                        }
+                        other => {
+                            return Some((other, KeyAction::Pressed));
+                        }
+                    }
+                }
+                KeyAction::Released => {
+                    let active = self.active.iter_mut().find(|slot| {
+                        slot.is_some_and(|active| {
+                            active.row == event.row && active.col == event.col
+                        })
+                    });
+                    match active.and_then(Option::take) {
+                        Some(active) => self.deactivate(active, event.timestamp),
+                        None => {
+                            return Some((keymap.get(event.row, event.col), KeyAction::Released))
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Decides a pending key from the events after it, or from the time
+    /// `now` if those aren't enough. If the buffer is full, the key is held
+    /// instead of waiting for more events.
+    fn decide(&self, pending: &Pending, now: Instant) -> Option<Decision> {
+        let term = self.config.tapping_term;
+        for (i, event) in self.buffer.iter().flatten().enumerate() {
+            if elapsed(pending.pressed_at, event.timestamp) >= term {
+                return Some(Decision::Hold);
+            }
+            let is_pending = event.row == pending.row && event.col == pending.col;
+            match event.action {
+                KeyAction::Released if is_pending => {
+                    return Some(Decision::Tap);
+                }
+                KeyAction::Pressed if self.config.hold_on_other_key_press => {
+                    return Some(Decision::Hold);
+                }
+                KeyAction::Released if self.config.permissive_hold => {
+                    // Only keys that were also pressed after the pending key.
+                    let tapped = self.buffer[..i].iter().flatten().any(|other| {
+                        other.row == event.row
+                            && other.col == event.col
+                            && other.action.is_pressed()
+                    });
+                    if tapped {
+                        return Some(Decision::Hold);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let full = self.buffer.iter().all(Option::is_some);
+        (full || elapsed(pending.pressed_at, now) >= term).then_some(Decision::Hold)
+    }
+
+    fn free_slots(&self) -> usize {
+        self.active.iter().filter(|slot| slot.is_none()).count()
+    }
+
+    fn pop_front(&mut self) -> Option<KeyEvent> {
+        let event = self.buffer[0].take()?;
+        self.buffer.rotate_left(1);
+        Some(event)
+    }
+
+    /// Advances a tap dance with the next event, or with the time `now` if
+    /// there are no events. Returns false if it needs to wait for more.
+    fn advance_dance(&mut self, mut dancing: Dancing, now: Instant) -> bool {
+        let term = self.config.tapping_term;
+        match self.buffer[0] {
+            Some(event)
+                if event.row == dancing.row
+                    && event.col == dancing.col
+                    && elapsed(dancing.since, event.timestamp) < term =>
+            {
+                self.pop_front();
+                if event.action.is_pressed() {
+                    dancing.count = dancing.count.saturating_add(1);
+                }
+                dancing.pressed = event.action.is_pressed();
+                dancing.since = event.timestamp;
+                let max_count = dancing.dance.taps.len().max(dancing.dance.holds.len());
+                if !dancing.pressed && usize::from(dancing.count) >= max_count {
+                    self.finish_dance(dancing);
+                } else {
+                    self.dancing = Some(dancing);
+                }
+            }
+            // Any other key interrupts the dance, as does waiting too long.
+            Some(_) => self.finish_dance(dancing),
+            None if elapsed(dancing.since, now) >= term => self.finish_dance(dancing),
+            None => return false,
+        }
+        true
+    }
+
+    fn finish_dance(&mut self, dancing: Dancing) {
+        self.dancing = None;
+        let taps = dancing.dance.taps;
+        let index = usize::from(dancing.count) - 1;
+        let tap = taps.get(index).or(taps.last()).copied().unwrap_or(KC_NO);
+        if dancing.pressed {
+            let hold = dancing.dance.holds.get(index).copied().unwrap_or(tap);
+            let _ = self.output.push_back((hold, KeyAction::Pressed));
+            self.add_active(dancing.row, dancing.col, Resolved::Dance(hold));
+        } else {
+            let _ = self.output.push_back((tap, KeyAction::Pressed));
+            let _ = self.output.push_back((tap, KeyAction::Released));
+        }
+    }
+
+    fn activate(&mut self, row: usize, col: usize, keycode: TapHoldKeycode, decision: Decision) {
+        self.emit(keycode, decision, KeyAction::Pressed);
+        self.add_active(row, col, Resolved::TapHold(keycode, decision));
+    }
+
+    /// Records a decided key, so that its keycodes are released along with
+    /// it. Keys are only decided if there is a free slot for them.
+    fn add_active(&mut self, row: usize, col: usize, resolved: Resolved) {
+        let slot = self.active.iter_mut().find(|slot| slot.is_none());
+        debug_assert!(slot.is_some(), "no slot for a decided key");
+        if let Some(slot) = slot {
+            *slot = Some(Active {
+                row,
+                col,
+                resolved,
+                interrupted: false,
+            });
+        }
+    }
+
+    fn deactivate(&mut self, active: Active, released_at: Instant) {
+        let (keycode, decision) = match active.resolved {
+            Resolved::TapHold(keycode, decision) => (keycode, decision),
+            Resolved::Dance(keycode) => {
+                let _ = self.output.push_back((keycode, KeyAction::Released));
+                return;
+            }
+            Resolved::Combo(keycode, id) => {
+                let _ = self.output.push_back((keycode, KeyAction::Released));
+                for other in self.active.iter_mut().flatten() {
+                    if matches!(other.resolved, Resolved::Combo(_, other_id) if other_id == id) {
+                        other.resolved = Resolved::Suppressed;
+                    }
+                }
+                return;
+            }
+            Resolved::Suppressed => return,
+        };
+        self.emit(keycode, decision, KeyAction::Released);
+        match decision {
+            Decision::Tap => {
+                self.last_tap = Some((active.row, active.col, released_at));
+            }
+            Decision::Hold if self.config.retro_tapping && !active.interrupted => {
+                self.emit(keycode, Decision::Tap, KeyAction::Pressed);
+                self.emit(keycode, Decision::Tap, KeyAction::Released);
+            }
+            Decision::Hold => {}
+        }
+    }
+
+    /// Queues the keycode events for pressing or releasing a decided key.
+    fn emit(&mut self, keycode: TapHoldKeycode, decision: Decision, action: KeyAction) {
+        match (decision, keycode.hold()) {
+            (Decision::Tap, _) => {
+                let _ = self.output.push_back((Keycode::Hid(keycode.tap()), action));
+            }
+            (Decision::Hold, Hold::Modifiers(mods)) => {
+                for (i, &modifier) in MODIFIERS.iter().enumerate() {
+                    if mods & (1 << i) != 0 {
+                        let _ = self.output.push_back((Keycode::Hid(modifier), action));
+                    }
+                }
+            }
+            (Decision::Hold, Hold::Layer(layer)) => {
+                let _ = self.output.push_back((MO(layer), action));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::combo::{Combo, ComboKey, ComboTable, Combos};
+    use crate::keycode::qmk::{
+        KC_A, KC_B, KC_C, KC_D, KC_E, KC_ESC, KC_LSFT, LT, MOD_LSFT, MT, TD,
+    };
+    use crate::keymap::Layered;
+
+    use std::vec::Vec;
+
+    static LAYERS: [[[Keycode; 6]; 1]; 1] =
+        [[[MT(MOD_LSFT, KC_A), LT(1, KC_B), KC_C, TD(0), KC_D, KC_E]]];
+
+    static DANCES: [Dance; 1] = [Dance {
+        taps: &[KC_A, KC_B],
+        holds: &[MO(1)],
+    }];
+
+    struct TestCombos;
+
+    impl Combos<1, 6> for TestCombos {
+        const LAYERS: &'static [[[Keycode; 6]; 1]] = &LAYERS;
+        const COMBOS: &'static [&'static [Combo]] = &[&[Combo {
+            keys: &[ComboKey::Keycode(KC_D), ComboKey::Position(0, 5)],
+            keycode: KC_ESC,
+        }]];
+    }
+
+    use KeyAction::{Pressed, Released};
+
+    fn try_push<const N: usize>(
+        engine: &mut TapHold<N>,
+        col: usize,
+        action: KeyAction,
+        ticks: u32,
+    ) -> Result<(), KeyEvent> {
+        engine.push(KeyEvent {
+            row: 0,
+            col,
+            action,
+            timestamp: Instant::from_ticks(ticks),
+        })
+    }
+
+    fn push<const N: usize>(engine: &mut TapHold<N>, col: usize, action: KeyAction, ticks: u32) {
+        assert!(try_push(engine, col, action, ticks).is_ok());
+    }
+
+    fn drain<const N: usize>(engine: &mut TapHold<N>, ticks: u32) -> Vec<(Keycode, KeyAction)> {
+        let keymap = Layered::new(&LAYERS)
+            .with_dances(&DANCES)
+            .with_combos(ComboTable::new::<TestCombos>());
+        let mut output = Vec::new();
+        while let Some(event) = engine.next(Instant::from_ticks(ticks), &keymap) {
+            output.push(event);
+        }
+        output
+    }
+
+    #[test]
+    fn tap() {
+        let mut engine = TapHold::<8>::new(Config::default());
+        push(&mut engine, 0, Pressed, 0);
+        assert!(drain(&mut engine, 10).is_empty());
+        push(&mut engine, 0, Released, 50);
+        assert!(drain(&mut engine, 50) == [(KC_A, Pressed), (KC_A, Released)]);
+    }
+
+    #[test]
+    fn hold() {
+        let mut engine = TapHold::<8>::new(Config::default());
+        push(&mut engine, 1, Pressed, 0);
+        assert!(drain(&mut engine, 199).is_empty());
+        assert!(drain(&mut engine, 200) == [(MO(1), Pressed)]);
+        push(&mut engine, 1, Released, 300);
+        assert!(drain(&mut engine, 300) == [(MO(1), Released)]);
+    }
+
+    #[test]
+    fn replays_in_order() {
+        let mut engine = TapHold::<8>::new(Config::default());
+        push(&mut engine, 0, Pressed, 0);
+        push(&mut engine, 2, Pressed, 10);
+        push(&mut engine, 2, Released, 20);
+        assert!(drain(&mut engine, 20).is_empty());
+        push(&mut engine, 0, Released, 30);
+        assert!(
+            drain(&mut engine, 30)
+                == [
+                    (KC_A, Pressed),
+                    (KC_C, Pressed),
+                    (KC_C, Released),
+                    (KC_A, Released)
+                ]
+        );
+    }
+
+    #[test]
+    fn hold_on_other_key_press() {
+        let mut engine = TapHold::<8>::new(Config {
+            hold_on_other_key_press: true,
+            ..Config::default()
+        });
+        push(&mut engine, 0, Pressed, 0);
+        push(&mut engine, 2, Pressed, 10);
+        assert!(drain(&mut engine, 10) == [(KC_LSFT, Pressed), (KC_C, Pressed)]);
+    }
+
+    #[test]
+    fn permissive_hold() {
+        let config = Config {
+            permissive_hold: true,
+            ..Config::default()
+        };
+
+        // A rolling press is still a tap.
+        let mut engine = TapHold::<8>::new(config);
+        push(&mut engine, 0, Pressed, 0);
+        push(&mut engine, 2, Pressed, 10);
+        assert!(drain(&mut engine, 10).is_empty());
+        push(&mut engine, 0, Released, 20);
+        push(&mut engine, 2, Released, 30);
+        assert!(
+            drain(&mut engine, 30)
+                == [
+                    (KC_A, Pressed),
+                    (KC_C, Pressed),
+                    (KC_A, Released),
+                    (KC_C, Released)
+                ]
+        );
+
+        // A nested tap makes it a hold.
+        let mut engine = TapHold::<8>::new(config);
+        push(&mut engine, 0, Pressed, 0);
+        push(&mut engine, 2, Pressed, 10);
+        push(&mut engine, 2, Released, 20);
+        assert!(drain(&mut engine, 20) == [(KC_LSFT, Pressed), (KC_C, Pressed), (KC_C, Released)]);
+    }
+
+    #[test]
+    fn retro_tapping() {
+        let mut engine = TapHold::<8>::new(Config {
+            retro_tapping: true,
+            ..Config::default()
+        });
+        push(&mut engine, 0, Pressed, 0);
+        push(&mut engine, 0, Released, 300);
+        assert!(
+            drain(&mut engine, 300)
+                == [
+                    (KC_LSFT, Pressed),
+                    (KC_LSFT, Released),
+                    (KC_A, Pressed),
+                    (KC_A, Released)
+                ]
+        );
+    }
+
+    #[test]
+    fn quick_tap() {
+        let mut engine = TapHold::<8>::new(Config::default());
+        push(&mut engine, 0, Pressed, 0);
+        push(&mut engine, 0, Released, 50);
+        push(&mut engine, 0, Pressed, 100);
+        assert!(drain(&mut engine, 100) == [(KC_A, Pressed), (KC_A, Released), (KC_A, Pressed)]);
+        assert!(drain(&mut engine, 500).is_empty());
+    }
+
+    #[test]
+    fn dance_taps() {
+        let mut engine = TapHold::<8>::new(Config::default());
+        push(&mut engine, 3, Pressed, 0);
+        push(&mut engine, 3, Released, 50);
+        assert!(drain(&mut engine, 249).is_empty());
+        assert!(drain(&mut engine, 250) == [(KC_A, Pressed), (KC_A, Released)]);
+
+        // The last tap of the dance finishes it right away.
+        push(&mut engine, 3, Pressed, 300);
+        push(&mut engine, 3, Released, 350);
+        push(&mut engine, 3, Pressed, 400);
+        push(&mut engine, 3, Released, 450);
+        assert!(drain(&mut engine, 450) == [(KC_B, Pressed), (KC_B, Released)]);
+    }
+
+    #[test]
+    fn dance_hold() {
+        let mut engine = TapHold::<8>::new(Config::default());
+        push(&mut engine, 3, Pressed, 0);
+        assert!(drain(&mut engine, 200) == [(MO(1), Pressed)]);
+        push(&mut engine, 3, Released, 500);
+        assert!(drain(&mut engine, 500) == [(MO(1), Released)]);
+    }
+
+    #[test]
+    fn dance_interrupted() {
+        let mut engine = TapHold::<8>::new(Config::default());
+        push(&mut engine, 3, Pressed, 0);
+        push(&mut engine, 3, Released, 50);
+        push(&mut engine, 2, Pressed, 100);
+        assert!(drain(&mut engine, 100) == [(KC_A, Pressed), (KC_A, Released), (KC_C, Pressed)]);
+    }
+
+    #[test]
+    fn combo() {
+        let mut engine = TapHold::<8>::new(Config::default());
+        push(&mut engine, 4, Pressed, 0);
+        assert!(drain(&mut engine, 0).is_empty());
+        push(&mut engine, 5, Pressed, 10);
+        assert!(drain(&mut engine, 10) == [(KC_ESC, Pressed)]);
+        push(&mut engine, 5, Released, 50);
+        assert!(drain(&mut engine, 50) == [(KC_ESC, Released)]);
+        push(&mut engine, 4, Released, 60);
+        assert!(drain(&mut engine, 60).is_empty());
+    }
+
+    #[test]
+    fn combo_timeout() {
+        let mut engine = TapHold::<8>::new(Config::default());
+        push(&mut engine, 4, Pressed, 0);
+        assert!(drain(&mut engine, 49).is_empty());
+        assert!(drain(&mut engine, 50) == [(KC_D, Pressed)]);
+        push(&mut engine, 5, Pressed, 60);
+        assert!(drain(&mut engine, 110) == [(KC_E, Pressed)]);
+    }
+
+    #[test]
+    fn combo_interrupted() {
+        let mut engine = TapHold::<8>::new(Config::default());
+        push(&mut engine, 4, Pressed, 0);
+        push(&mut engine, 4, Released, 20);
+        assert!(drain(&mut engine, 20) == [(KC_D, Pressed), (KC_D, Released)]);
+    }
+
+    #[test]
+    fn full_buffer() {
+        let mut engine = TapHold::<2>::new(Config::default());
+        push(&mut engine, 0, Pressed, 0);
+        push(&mut engine, 2, Pressed, 10);
+        assert!(try_push(&mut engine, 2, Released, 20).is_err());
+
+        // Taking events makes room, and once the buffer is full again, the
+        // undecided key is held, well before the tapping term.
+        assert!(drain(&mut engine, 20).is_empty());
+        push(&mut engine, 2, Released, 20);
+        assert!(drain(&mut engine, 20) == [(KC_LSFT, Pressed), (KC_C, Pressed), (KC_C, Released)]);
+        push(&mut engine, 0, Released, 30);
+        assert!(drain(&mut engine, 30) == [(KC_LSFT, Released)]);
+    }
+
+    #[test]
+    fn full_active_keys() {
+        let mut engine = TapHold::<2>::new(Config::default());
+        push(&mut engine, 0, Pressed, 0);
+        push(&mut engine, 1, Pressed, 10);
+        assert!(drain(&mut engine, 300) == [(KC_LSFT, Pressed), (MO(1), Pressed)]);
+
+        // With no slot left, the dance key is sent as it is.
+        push(&mut engine, 3, Pressed, 300);
+        assert!(drain(&mut engine, 300) == [(TD(0), Pressed)]);
+
+        push(&mut engine, 3, Released, 400);
+        push(&mut engine, 1, Released, 410);
+        assert!(drain(&mut engine, 410) == [(TD(0), Released), (MO(1), Released)]);
+        push(&mut engine, 0, Released, 420);
+        assert!(drain(&mut engine, 420) == [(KC_LSFT, Released)]);
+    }
+}
@@ -0,0 +1,92 @@
+//! Time keeping.
+
+use core::cell::Cell;
+
+/// A point in time, with millisecond resolution.
+///
+/// The underlying tick counter is allowed to wrap around; comparisons between
+/// instants that are less than half the counter range apart remain correct.
+pub type Instant = fugit::TimerInstantU32<1_000>;
+
+/// A span of time, with millisecond resolution.
+pub type Duration = fugit::TimerDurationU32<1_000>;
+
+/// A source of monotonic time.
+pub trait Clock {
+    /// The current time.
+    fn now(&self) -> Instant;
+}
+
+impl<F> Clock for F
+where
+    F: Fn() -> Instant,
+{
+    fn now(&self) -> Instant {
+        self()
+    }
+}
+
+/// A clock that only moves when it is told to, for host tests.
+pub struct MockClock {
+    now: Cell<Instant>,
+}
+
+impl MockClock {
+    /// Creates a clock stopped at tick zero.
+    pub const fn new() -> Self {
+        Self {
+            now: Cell::new(Instant::from_ticks(0)),
+        }
+    }
+
+    pub fn set(&self, now: Instant) {
+        self.now.set(now);
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration);
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+}
+
+/// Whether at least `delay` has passed between `since` and `now`.
+///
+/// Timestamps that appear to be in the future have wrapped around the tick
+/// counter, and so are treated as long expired.
+pub(crate) fn has_elapsed(now: Instant, since: Instant, delay: Duration) -> bool {
+    now.checked_duration_since(since)
+        .is_none_or(|elapsed| elapsed >= delay)
+}
+
+/// The time from `since` to `at`, or zero if `at` is earlier.
+pub(crate) fn elapsed(since: Instant, at: Instant) -> Duration {
+    at.checked_duration_since(since)
+        .unwrap_or(Duration::from_ticks(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock_wraps_around() {
+        let clock = MockClock::new();
+        clock.set(Instant::from_ticks(u32::MAX - 1));
+        let before = clock.now();
+        clock.advance(Duration::millis(3));
+        assert!(clock.now().ticks() == 1);
+        assert!(clock.now() > before);
+        assert!(clock.now().checked_duration_since(before) == Some(Duration::millis(3)));
+    }
+}
@@ -16,6 +16,11 @@ pub trait Uplink {
 
     /// Release all pressed keys except for modifiers.
     fn clear_keyboard_but_mods(&mut self) -> Result<(), Self::Error>;
+
+    /// Whether the host has suspended the link.
+    fn is_suspended(&self) -> bool {
+        false
+    }
 }
 
 #[cfg(feature = "usb")]
@@ -23,7 +28,7 @@ pub mod usb {
     use super::Uplink;
     use crate::keycode::{HidKeycode, KeyAction, Keycode};
     use usb_device::bus::{UsbBus, UsbBusAllocator};
-    use usb_device::device::UsbDevice;
+    use usb_device::device::{UsbDevice, UsbDeviceState};
     use usb_device::UsbError;
     use usbd_hid::descriptor::{KeyboardReport, SerializedDescriptor};
     use usbd_hid::hid_class::HIDClass;
@@ -153,5 +158,9 @@ pub mod usb {
             self.pending = true;
             Ok(())
         }
+
+        fn is_suspended(&self) -> bool {
+            self.device.state() == UsbDeviceState::Suspend
+        }
     }
 }